@@ -2,7 +2,13 @@
 
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, ToTokens};
-use syn::{ItemTrait, LifetimeParam, Type, TypePath, TypeReference};
+use syn::{
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    visit::{self, Visit},
+    visit_mut::{self, VisitMut},
+    ItemTrait, LifetimeParam, Token, Type, TypePath, TypeReference,
+};
 
 #[derive(Clone)]
 enum ReceiverType {
@@ -10,6 +16,12 @@ enum ReceiverType {
     Ref(TypeReference),
     Mut(TypeReference),
     Owned,
+    /// `self: Box<Self>`
+    Boxed(Type),
+    /// `self: Rc<Self>` or `self: Arc<Self>`
+    Shared(Type),
+    /// `self: Pin<&mut Self>`
+    PinMut(Type),
 }
 
 impl std::fmt::Debug for ReceiverType {
@@ -25,6 +37,18 @@ impl std::fmt::Debug for ReceiverType {
                 .field(&format_token_stream(arg0))
                 .finish(),
             Self::Owned => write!(f, "Owned"),
+            Self::Boxed(arg0) => f
+                .debug_tuple("Boxed")
+                .field(&format_token_stream(arg0))
+                .finish(),
+            Self::Shared(arg0) => f
+                .debug_tuple("Shared")
+                .field(&format_token_stream(arg0))
+                .finish(),
+            Self::PinMut(arg0) => f
+                .debug_tuple("PinMut")
+                .field(&format_token_stream(arg0))
+                .finish(),
         }
     }
 }
@@ -34,6 +58,11 @@ impl PartialEq for ReceiverType {
         match (self, other) {
             (Self::Ref(l0), Self::Ref(r0)) => format_token_stream(l0) == format_token_stream(r0),
             (Self::Mut(l0), Self::Mut(r0)) => format_token_stream(l0) == format_token_stream(r0),
+            (Self::Boxed(l0), Self::Boxed(r0))
+            | (Self::Shared(l0), Self::Shared(r0))
+            | (Self::PinMut(l0), Self::PinMut(r0)) => {
+                format_token_stream(l0) == format_token_stream(r0)
+            }
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -43,7 +72,132 @@ fn format_token_stream(t: &impl ToTokens) -> String {
     format!("{}", t.to_token_stream())
 }
 
-fn expend(input: ItemTrait) -> Result<TokenStream, String> {
+fn is_self(t: &Type) -> bool {
+    matches!(t, Type::Path(p) if p.qself.is_none() && p.path.is_ident("Self"))
+}
+
+fn receiver_of_type(t: &Type) -> Option<ReceiverType> {
+    match t {
+        Type::Reference(r) if is_self(&r.elem) => Some(if r.mutability.is_some() {
+            ReceiverType::Mut(r.clone())
+        } else {
+            ReceiverType::Ref(r.clone())
+        }),
+        _ if is_self(t) => Some(ReceiverType::Owned),
+        Type::Path(p) if p.qself.is_none() => {
+            let last = p.path.segments.last()?;
+            let syn::PathArguments::AngleBracketed(generics) = &last.arguments else {
+                return None;
+            };
+            let arg = match generics.args.first() {
+                Some(syn::GenericArgument::Type(v)) if generics.args.len() == 1 => v,
+                _ => return None,
+            };
+            match last.ident.to_string().as_str() {
+                "Box" if is_self(arg) => Some(ReceiverType::Boxed(t.clone())),
+                "Rc" | "Arc" if is_self(arg) => Some(ReceiverType::Shared(t.clone())),
+                "Pin" => match arg {
+                    Type::Reference(r) if r.mutability.is_some() && is_self(&r.elem) => {
+                        Some(ReceiverType::PinMut(t.clone()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// the lifetime of `&self`, `&mut self` or `self: Pin<&mut Self>`
+fn receiver_lifetime(r: &ReceiverType) -> Option<syn::Lifetime> {
+    match r {
+        ReceiverType::Ref(r) | ReceiverType::Mut(r) => r.lifetime.clone(),
+        ReceiverType::PinMut(t) => {
+            let mut lifetime = None;
+            struct FindReference<'a>(&'a mut Option<syn::Lifetime>);
+            impl<'ast> Visit<'ast> for FindReference<'_> {
+                fn visit_type_reference(&mut self, i: &'ast TypeReference) {
+                    *self.0 = i.lifetime.clone();
+                }
+            }
+            FindReference(&mut lifetime).visit_type(t);
+            lifetime
+        }
+        _ => None,
+    }
+}
+
+/// errors that are reported together
+#[derive(Default)]
+struct Errors(Option<syn::Error>);
+
+impl Errors {
+    fn push(&mut self, e: syn::Error) {
+        match &mut self.0 {
+            Some(v) => v.combine(e),
+            None => self.0 = Some(e),
+        }
+    }
+    fn finish(self) -> syn::Result<()> {
+        self.0.map_or(Ok(()), Err)
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    /// implement methods returning `impl Future` for `AsyncFn*` closures
+    async_fn: bool,
+    /// take type parameters of the method as `dyn Trait` in the closure
+    erase_generics: bool,
+    /// emit `unsafe impl` for an unsafe trait, the user promises closures uphold its contract
+    unsafe_impl: bool,
+    /// `Name = Type`, associated types that are fixed instead of inferred
+    associate_types: Vec<(Ident, Type)>,
+    /// `NAME = expr`, values of associated consts,
+    /// paths like `NAME = u8::MAX` are parsed as types and moved here later
+    associate_consts: Vec<(Ident, syn::Expr)>,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Args::default();
+        let mut seen: Vec<Ident> = vec![];
+        while !input.is_empty() {
+            let ident: Ident = input.parse()?;
+            if seen.contains(&ident) {
+                Err(syn::Error::new(ident.span(), "duplicate argument"))?
+            }
+            seen.push(ident.clone());
+            if input.peek(Token![=]) {
+                input.parse::<Token![=]>()?;
+                let fork = input.fork();
+                match fork.parse::<Type>() {
+                    Ok(_) if fork.is_empty() || fork.peek(Token![,]) => {
+                        args.associate_types.push((ident, input.parse()?));
+                    }
+                    _ => args.associate_consts.push((ident, input.parse()?)),
+                }
+            } else {
+                match ident.to_string().as_str() {
+                    "async_fn" => args.async_fn = true,
+                    "erase_generics" => args.erase_generics = true,
+                    "unsafe_impl" => args.unsafe_impl = true,
+                    _ => Err(syn::Error::new(ident.span(), "unknown argument"))?,
+                }
+            }
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+        Ok(args)
+    }
+}
+
+fn expend(input: ItemTrait, args: Args) -> syn::Result<TokenStream> {
+    // independent problems are reported together
+    let mut errors = Errors::default();
     // if input.generics.gt_token.is_some() || input.generics.lt_token.is_some() {
     //     Err("Generics not supported ")?
     // }
@@ -55,39 +209,69 @@ fn expend(input: ItemTrait) -> Result<TokenStream, String> {
         .where_clause
         .map(|w| w.predicates.iter().cloned().collect::<Vec<_>>())
         .unwrap_or_default();
-    if input.unsafety.is_some() {
-        Err("unsafe trait not supported")?
+    match (&input.unsafety, args.unsafe_impl) {
+        (Some(v), false) => errors.push(syn::Error::new_spanned(
+            v,
+            "unsafe trait needs `#[functional_trait(unsafe_impl)]`",
+        )),
+        (None, true) => errors.push(syn::Error::new_spanned(
+            input.trait_token,
+            "`unsafe_impl` can only be used on an unsafe trait",
+        )),
+        _ => {}
     }
+    let trait_is_unsafe = input.unsafety.is_some();
     let supertraits: Vec<syn::TypeParamBound> =
         input.supertraits.iter().cloned().collect::<Vec<_>>();
     // println!("{}", quote!(#(#supertraits),*));
     let trait_name = input.ident.clone();
     let func = {
-        let item = if input
-            .items
-            .iter()
-            .filter(|v| {
-                //
-                !matches!(v, syn::TraitItem::Type(_))
-            })
-            .count()
-            != 1
-        {
-            Err("need exactly 1 fn")?
-        } else {
-            input
+        // provided methods and consts are left to the trait
+        let is_required = |v: &&syn::TraitItem| match v {
+            syn::TraitItem::Type(_) | syn::TraitItem::Const(_) => false,
+            syn::TraitItem::Fn(f) => f.default.is_none(),
+            _ => true,
+        };
+        let mut items = input.items.iter().filter(is_required).collect::<Vec<_>>();
+        if items.is_empty() {
+            // a single method with a default body is overridden,
+            // patterns in arguments are only allowed with a body
+            items = input
                 .items
                 .iter()
-                .find(|v| {
-                    //
-                    !matches!(v, syn::TraitItem::Type(_))
-                })
-                .unwrap()
-                .clone()
-        };
-        match item {
-            syn::TraitItem::Fn(f) => f,
-            _ => Err("need fn")?,
+                .filter(|v| matches!(v, syn::TraitItem::Fn(_)))
+                .collect();
+        }
+        // the trait is not cfg-stripped yet, so `#[cfg]` can not pick one of the methods,
+        // the `#[cfg]` of a single method is kept on the impl
+        if items.len() > 1 {
+            items.retain(|v| {
+                let syn::TraitItem::Fn(f) = v else {
+                    return true;
+                };
+                let cfg = f.attrs.iter().filter(|a| a.path().is_ident("cfg"));
+                cfg.clone().for_each(|a| {
+                    errors.push(syn::Error::new_spanned(
+                        a,
+                        "`#[cfg]` can not pick the method, leave only one method without a default body",
+                    ))
+                });
+                cfg.count() == 0
+            });
+        }
+        if items.is_empty() {
+            errors.push(syn::Error::new_spanned(&input.ident, "need exactly 1 fn"));
+        }
+        for v in items.iter().skip(1) {
+            errors.push(syn::Error::new_spanned(v, "need exactly 1 fn"));
+        }
+        match items.first() {
+            Some(syn::TraitItem::Fn(f)) => Some(f.clone()),
+            Some(v) => {
+                errors.push(syn::Error::new_spanned(v, "need fn"));
+                None
+            }
+            None => None,
         }
     };
     let associate_types: Vec<syn::TraitItemType> = {
@@ -103,17 +287,138 @@ fn expend(input: ItemTrait) -> Result<TokenStream, String> {
             })
             .collect::<Vec<_>>()
     };
+    let mut args = args;
+    let associate_consts_ori: Vec<syn::TraitItemConst> = {
+        input
+            .items
+            .iter()
+            .filter_map(|v| {
+                //
+                match v {
+                    syn::TraitItem::Const(c) => Some(c.clone()),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let (fixed_types, fixed_consts): (Vec<_>, Vec<_>) =
+        args.associate_types.into_iter().partition(|(ident, _)| {
+            associate_types.iter().any(|v| v.ident == *ident)
+                || !associate_consts_ori.iter().any(|v| v.ident == *ident)
+        });
+    args.associate_types = fixed_types;
+    for (ident, t) in fixed_consts {
+        match syn::parse2(t.into_token_stream()) {
+            Ok(e) => args.associate_consts.push((ident, e)),
+            Err(e) => errors.push(e),
+        }
+    }
+    for (ident, _) in args
+        .associate_types
+        .iter()
+        .filter(|(ident, _)| !associate_types.iter().any(|v| v.ident == *ident))
+    {
+        errors.push(syn::Error::new_spanned(
+            ident,
+            format!("no associated type named `{}`", ident),
+        ));
+    }
+    for (ident, _) in args
+        .associate_consts
+        .iter()
+        .filter(|(ident, _)| !associate_consts_ori.iter().any(|v| v.ident == *ident))
+    {
+        errors.push(syn::Error::new_spanned(
+            ident,
+            format!("no associated const named `{}`", ident),
+        ));
+    }
+    // consts with a default are only defined if a value is given
+    let associate_consts: Vec<(syn::TraitItemConst, syn::Expr)> = {
+        let mut v = vec![];
+        for c in &associate_consts_ori {
+            match args
+                .associate_consts
+                .iter()
+                .find(|(ident, _)| *ident == c.ident)
+            {
+                Some((_, e)) => v.push((c.clone(), e.clone())),
+                None if c.default.is_none() => errors.push(syn::Error::new_spanned(
+                    &c.ident,
+                    format!(
+                        "associated const `{0}` needs a value, use `#[functional_trait({0} = ..)]`",
+                        c.ident
+                    ),
+                )),
+                None => {}
+            }
+        }
+        v
+    };
 
+    // the rest is about the method
+    let Some(func) = func else {
+        errors.finish()?;
+        unreachable!("a missing fn is always an error")
+    };
     let func_sig = { func.sig.clone() };
+    let func_where: Vec<syn::WherePredicate> = func_sig
+        .generics
+        .where_clause
+        .as_ref()
+        .map(|w| w.predicates.iter().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
 
     let func_is_unsafe = func_sig.unsafety.is_some();
-    if func_sig.generics.type_params().next().is_some()
-        || func_sig.generics.const_params().next().is_some()
-    {
-        Err("fn with generic types not supported")?
+    let func_is_async = func_sig.asyncness.is_some();
+    let func_abi = func_sig.abi.clone();
+    // attributes that mean the same on the impl, `#[must_use]` and docs stay on the trait
+    let func_attrs: Vec<syn::Attribute> = func
+        .attrs
+        .iter()
+        .filter(|v| {
+            let forwarded = [
+                "inline",
+                "cold",
+                "track_caller",
+                "cfg",
+                "cfg_attr",
+                "allow",
+                "warn",
+                "deny",
+                "forbid",
+                "expect",
+            ];
+            forwarded.iter().any(|a| v.path().is_ident(a))
+        })
+        .cloned()
+        .collect();
+    if let Some(v) = &func_sig.variadic {
+        errors.push(syn::Error::new_spanned(
+            v,
+            "variadic fn not supported, only foreign fns can be variadic",
+        ));
+    }
+    if let Some(v) = &func_sig.constness {
+        errors.push(syn::Error::new_spanned(v, "const fn not supported"));
+    }
+    for v in func_sig.generics.const_params() {
+        errors.push(syn::Error::new_spanned(
+            v,
+            "fn with generic types not supported",
+        ));
+    }
+    for v in func_sig.generics.type_params() {
+        if !args.erase_generics {
+            errors.push(syn::Error::new_spanned(
+                v,
+                "fn with generic types not supported",
+            ));
+        }
     }
+    let func_type_params: Vec<syn::TypeParam> = func_sig.generics.type_params().cloned().collect();
 
-    let func_liftimes: Vec<LifetimeParam> = {
+    let mut func_liftimes: Vec<LifetimeParam> = {
         func_sig
             .generics
             .lifetimes()
@@ -125,35 +430,27 @@ fn expend(input: ItemTrait) -> Result<TokenStream, String> {
 
     // let func_inputs = func_sig.inputs;
 
-    let self_input: ReceiverType = {
+    let mut self_input: ReceiverType = {
         match func_sig.inputs.first() {
             Some(s) => match s.clone() {
-                syn::FnArg::Receiver(r) => {
-                    if r.colon_token.is_some() {
-                        Err("must be &self, &mut self, Self")?
-                    }
-                    // println!("{}", r.mutability.to_token_stream());
-                    if r.mutability.is_some() {
-                        ReceiverType::Mut(match &*r.ty {
-                            syn::Type::Reference(r) => r.clone(),
-                            _ => unreachable!(),
-                        })
-                    } else {
-                        match &*r.ty {
-                            syn::Type::Path(_) => ReceiverType::Owned,
-                            syn::Type::Reference(r) => ReceiverType::Ref(r.clone()),
-                            _ => unreachable!(),
-                        }
+                syn::FnArg::Receiver(r) => match receiver_of_type(&r.ty) {
+                    Some(v) => v,
+                    None => {
+                        errors.push(syn::Error::new_spanned(r, "must be &self, &mut self, self, self: Box<Self>, self: Rc<Self>, self: Arc<Self> or self: Pin<&mut Self>"));
+                        ReceiverType::None
                     }
-                }
+                },
                 syn::FnArg::Typed(_) => ReceiverType::None,
             },
             None => ReceiverType::None,
         }
     };
 
-    if self_input == ReceiverType::None {
-        Err("must have a receiver")?
+    if !matches!(func_sig.inputs.first(), Some(syn::FnArg::Receiver(_))) {
+        errors.push(syn::Error::new_spanned(
+            &func_sig.ident,
+            "must have a receiver",
+        ));
     }
 
     let func_inputs = {
@@ -161,54 +458,317 @@ fn expend(input: ItemTrait) -> Result<TokenStream, String> {
             .inputs
             .iter()
             .enumerate()
-            .filter(|(id, _v)| !(*id == 0 && self_input != ReceiverType::None))
-            .map(|(_id, v)| v)
+            .filter_map(|(id, v)| match v {
+                syn::FnArg::Receiver(_) if id == 0 => None,
+                syn::FnArg::Receiver(r) => {
+                    errors.push(syn::Error::new_spanned(
+                        r,
+                        "no receiver except for the first arg",
+                    ));
+                    None
+                }
+                syn::FnArg::Typed(t) => Some(t),
+            })
             .collect::<Vec<_>>()
     };
 
     let func_arg_ids: Vec<Ident> = func_inputs
         .iter()
-        .map(|arg| match arg {
-            syn::FnArg::Receiver(_) => Err("no receiver except for the first arg"),
-            syn::FnArg::Typed(t) => match &*t.pat {
-                syn::Pat::Ident(i) => Ok(i.ident.clone()),
-                _ => unreachable!(),
-            },
+        .enumerate()
+        .map(|(id, t)| match &*t.pat {
+            syn::Pat::Ident(i) if i.by_ref.is_none() && i.subpat.is_none() => i.ident.clone(),
+            // `_`, `mut`/`ref` bindings and destructuring patterns get a fresh name
+            _ => ident_of_arg(id),
         })
-        .collect::<Result<Vec<_>, _>>()?;
+        .collect();
 
-    let func_arg_tys: Vec<Type> = func_inputs
-        .iter()
-        .map(|arg| match arg {
-            syn::FnArg::Receiver(_) => Err("no receiver except for the first arg"),
-            syn::FnArg::Typed(t) => Ok((*t.ty).clone()),
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+    let func_arg_attrs: Vec<Vec<syn::Attribute>> =
+        func_inputs.iter().map(|t| t.attrs.clone()).collect();
+    // the closure always takes every argument
+    for v in func_arg_attrs.iter().flatten() {
+        if v.path().is_ident("cfg") || v.path().is_ident("cfg_attr") {
+            errors.push(syn::Error::new_spanned(
+                v,
+                "`#[cfg]` and `#[cfg_attr]` on arguments not supported",
+            ));
+        }
+    }
+
+    let mut func_arg_tys: Vec<Type> = func_inputs.iter().map(|t| (*t.ty).clone()).collect();
+
+    // the closure would have to take itself, which can not be written
+    let mut find_self = FindSelf {
+        trait_name: &trait_name,
+        associate_types: &associate_types,
+        found: vec![],
+    };
+    func_arg_tys.iter().for_each(|v| find_self.visit_type(v));
+    for v in &find_self.found {
+        errors.push(syn::Error::new_spanned(v, "`Self` can not be used in argument types, a closure can not take itself as an argument"));
+    }
+
+    // erased type parameters are `dyn Trait` of their bounds in the closure
+    let func_type_params_dyn: Vec<(Ident, Type)> = {
+        let mut v = vec![];
+        for p in func_type_params.iter().filter(|_| args.erase_generics) {
+            let ident = &p.ident;
+            let bounds = p
+                .bounds
+                .iter()
+                .chain(func_where.iter().flat_map(|w| match w {
+                    syn::WherePredicate::Type(t) if is_type_param(&t.bounded_ty, ident) => {
+                        t.bounds.iter().collect()
+                    }
+                    _ => vec![],
+                }))
+                .collect::<Vec<_>>();
+            let dyn_type = match dyn_of_bounds(
+                &format!("erased type parameter `{}`", ident),
+                ident,
+                &bounds,
+            ) {
+                Ok(v) => v,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            let used_by_type = |t: &Type| match t {
+                Type::Reference(r) => is_type_param(&r.elem, ident),
+                t => is_type_param(t, ident),
+            };
+            let used_in_output = match &func_sig.output {
+                syn::ReturnType::Default => false,
+                syn::ReturnType::Type(_, t) => mentions_ident(t.to_token_stream(), ident),
+            };
+            let misused = match &func_sig.output {
+                syn::ReturnType::Type(_, t) if used_in_output => Some(&**t),
+                _ => func_arg_tys
+                    .iter()
+                    .find(|t| !used_by_type(t) && mentions_ident(t.to_token_stream(), ident)),
+            };
+            if let Some(t) = misused {
+                errors.push(syn::Error::new_spanned(t, format!(
+                    "erased type parameter `{}` can only be used as `{0}`, `&{0}` or `&mut {0}` in arguments",
+                    ident
+                )));
+            }
+            v.push((ident.clone(), dyn_type));
+        }
+        v
+    };
+
+    // `impl Trait` in arguments is erased the same way as a type parameter
+    for (id, t) in func_arg_ids.iter().zip(&func_arg_tys) {
+        if hoist_impl_traits(t, |_| void_type()).1.is_empty() {
+            continue;
+        }
+        if !args.erase_generics {
+            errors.push(syn::Error::new_spanned(
+                t,
+                "`impl Trait` in arguments needs `#[functional_trait(erase_generics)]`",
+            ));
+            continue;
+        }
+        let bounds = match t {
+            Type::Reference(r) => &*r.elem,
+            t => t,
+        };
+        let bounds = match bounds {
+            Type::Paren(p) => &*p.elem,
+            t => t,
+        };
+        let Type::ImplTrait(impl_trait) = bounds else {
+            errors.push(syn::Error::new_spanned(t, "`impl Trait` can only be used as `impl Trait`, `&impl Trait` or `&mut impl Trait` in arguments"));
+            continue;
+        };
+        let bounds = impl_trait.bounds.iter().collect::<Vec<_>>();
+        match dyn_of_bounds(
+            &format!("erased `impl Trait` of argument `{}`", id),
+            impl_trait,
+            &bounds,
+        ) {
+            Ok(v) if !hoist_impl_traits(&v, |_| void_type()).1.is_empty() => {
+                errors.push(syn::Error::new_spanned(
+                    impl_trait,
+                    "`impl Trait` can not be nested in an erased `impl Trait` argument",
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(e),
+        }
+    }
 
-    let func_out_type: FuncOutput = 'a: {
+    let mut func_out_type: FuncOutput = 'a: {
         let t = match &func_sig.output {
+            syn::ReturnType::Default if func_is_async => break 'a FuncOutput::Async(void_type()),
             syn::ReturnType::Default => break 'a FuncOutput::Type(void_type()),
+            syn::ReturnType::Type(_, b) if func_is_async => {
+                break 'a FuncOutput::Async((**b).clone())
+            }
             syn::ReturnType::Type(_, b) => &**b,
         };
-        let trait_impl = if let Type::ImplTrait(v) = t {
-            v
+        if args.async_fn {
+            let Type::ImplTrait(trait_impl) = t else {
+                errors.push(syn::Error::new_spanned(
+                    t,
+                    "with `async_fn` the fn must be async or return `impl Future<Output = ..>`",
+                ));
+                break 'a FuncOutput::Type(t.clone());
+            };
+            match future_output(&trait_impl.bounds) {
+                Some(v) => break 'a FuncOutput::Async(v),
+                None => {
+                    errors.push(syn::Error::new_spanned(
+                        t,
+                        "async closures can only return `impl Future<Output = ..>`",
+                    ));
+                    break 'a FuncOutput::Type(t.clone());
+                }
+            }
+        }
+        if hoist_impl_traits(t, |_| void_type()).1.is_empty() {
+            FuncOutput::Type(t.clone())
         } else {
-            break 'a FuncOutput::Type(t.clone());
-        };
-        FuncOutput::Impl(
-            trait_impl
-                .bounds
-                .iter()
-                .map(|v| v.to_owned())
-                .collect::<Vec<_>>(),
-        )
+            FuncOutput::Impl(t.clone())
+        }
     };
 
+    if associate_types
+        .iter()
+        .any(|v| !v.generics.params.is_empty())
+    {
+        for v in associate_types.iter().flat_map(|v| &v.generics.params) {
+            if !matches!(v, syn::GenericParam::Lifetime(_)) {
+                errors.push(syn::Error::new_spanned(
+                    v,
+                    "only lifetimes are supported in generic associated types",
+                ));
+            }
+        }
+        for (ident, _) in args.associate_types.iter().filter(|(ident, _)| {
+            associate_types
+                .iter()
+                .any(|v| v.ident == *ident && !v.generics.params.is_empty())
+        }) {
+            errors.push(syn::Error::new_spanned(
+                ident,
+                format!("generic associated type `{}` can not be fixed", ident),
+            ));
+        }
+        for t in &func_arg_tys {
+            let mut find_in_args = FindGats {
+                trait_name: &trait_name,
+                associate_types: &associate_types,
+                found: vec![],
+            };
+            find_in_args.visit_type(t);
+            if !find_in_args.found.is_empty() {
+                errors.push(syn::Error::new_spanned(
+                    t,
+                    "generic associated types can only be used in the return type",
+                ));
+            }
+        }
+        match func_out_type.clone() {
+            FuncOutput::Type(t) => {
+                let mut find = FindGats {
+                    trait_name: &trait_name,
+                    associate_types: &associate_types,
+                    found: vec![],
+                };
+                find.visit_type(&t);
+                for v in associate_types.iter().filter(|v| {
+                    !v.generics.params.is_empty()
+                        && !find.found.iter().any(|(ident, _)| *ident == v.ident)
+                }) {
+                    errors.push(syn::Error::new_spanned(
+                        &v.ident,
+                        format!(
+                            "generic associated type `{}` must be used in the return type",
+                            v.ident
+                        ),
+                    ));
+                }
+                // the helper trait is generic over the lifetimes the output depends on,
+                // so every use must name all of them to be defined from the helper trait
+                if let Some((_, lifetimes)) = find.found.first() {
+                    let is_method_lifetime =
+                        |l: &syn::Lifetime| func_liftimes.iter().any(|v| v.lifetime == *l);
+                    if find.found.iter().any(|(_, v)| {
+                        v.len() != lifetimes.len()
+                            || !v
+                                .iter()
+                                .all(|l| lifetimes.contains(l) && is_method_lifetime(l))
+                            || v.iter().enumerate().any(|(i, l)| v[..i].contains(l))
+                    }) {
+                        errors.push(syn::Error::new_spanned(
+                            &t,
+                            "generic associated types must be used with the same lifetimes of the method",
+                        ));
+                    }
+                    func_out_type = FuncOutput::Gat(t, lifetimes.clone());
+                }
+            }
+            _ => errors.push(syn::Error::new_spanned(&func_sig.output, "generic associated types can only be used in a return type that is not `impl Trait` or async")),
+        }
+    }
+
+    errors.finish()?;
+
+    if let FuncOutput::Impl(t) = &mut func_out_type {
+        let mut captures = CapturesLifetimes {
+            lifetimes: &func_liftimes,
+            found: false,
+        };
+        let bounds = hoist_impl_traits(t, |_| void_type()).1;
+        bounds
+            .iter()
+            .flatten()
+            .for_each(|v| captures.visit_type_param_bound(v));
+        // without `use<..>` the output captures every lifetime of the method
+        if bounds
+            .iter()
+            .any(|v| !v.iter().any(|v| precise_capture(v).is_some()))
+        {
+            captures.found |= !func_liftimes.is_empty();
+            match &self_input {
+                ReceiverType::Ref(r) | ReceiverType::Mut(r) => captures.visit_type_reference(r),
+                ReceiverType::PinMut(t) => captures.visit_type(t),
+                _ => {}
+            }
+            func_arg_tys.iter().for_each(|v| captures.visit_type(v));
+        }
+        if captures.found {
+            // the output is a different type for every lifetime of the method,
+            // so all of them need a name to be passed to the helper trait
+            let mut namer = NameElidedLifetimes::default();
+            match &mut self_input {
+                ReceiverType::Ref(r) | ReceiverType::Mut(r) => namer.visit_type_reference_mut(r),
+                ReceiverType::PinMut(t) => namer.visit_type_mut(t),
+                _ => {}
+            }
+            func_arg_tys
+                .iter_mut()
+                .for_each(|v| namer.visit_type_mut(v));
+            namer.output = match receiver_lifetime(&self_input) {
+                Some(v) => Some(v),
+                None if namer.new_lifetimes.len() == 1 => namer.new_lifetimes.first().cloned(),
+                None => None,
+            };
+            namer.visit_type_mut(t);
+            func_liftimes.extend(namer.new_lifetimes.into_iter().map(LifetimeParam::new));
+            func_out_type = FuncOutput::HigherRanked(t.clone());
+        }
+    }
+
     // print_token_vec(&func_arg_ids);
     // print_token_vec(&func_arg_tys);
     // println!("{}", void_type().into_token_stream());
 
-    let trait_impl = gen_impl(
+    let trait_impl = gen_impl(ImplInput {
+        trait_vis: input.vis,
+        trait_is_unsafe,
         trait_name,
         func_name,
         self_input,
@@ -216,12 +776,21 @@ fn expend(input: ItemTrait) -> Result<TokenStream, String> {
         func_arg_tys,
         func_out_type,
         func_liftimes,
+        func_type_params,
+        func_type_params_dyn,
         func_is_unsafe,
+        func_is_async,
+        func_abi,
+        func_attrs,
+        func_arg_attrs,
         supertraits,
         trait_generics,
         trait_where,
+        func_where,
         associate_types,
-    );
+        associate_types_fixed: args.associate_types,
+        associate_consts,
+    });
 
     let expanded = quote!(
         #trait_impl
@@ -231,116 +800,578 @@ fn expend(input: ItemTrait) -> Result<TokenStream, String> {
     // abort()
     Ok(expanded)
 }
+#[derive(Clone)]
 enum FuncOutput {
     Type(Type),
-    Impl(Vec<syn::TypeParamBound>),
+    /// a type with `impl Trait` in it
+    Impl(Type),
+    /// the closure is an async closure, and this is the output of its future
+    Async(Type),
+    /// a type with `impl Trait` that captures lifetimes of the method,
+    /// implemented through a helper trait that is generic over these lifetimes
+    HigherRanked(Type),
+    /// a type that uses generic associated types with these lifetimes of the method,
+    /// implemented through a helper trait that is generic over them
+    Gat(Type, Vec<syn::Lifetime>),
 }
-// fn token_vec(vec: &Vec<impl ToTokens>) -> TokenStream {
-//     let tokens = vec.iter().map(ToTokens::into_token_stream).collect();
-//     tokens
-// }
-// fn print_token_vec(vec: &Vec<impl ToTokens>) {
-//     println!("{}", token_vec(vec));
-// }
 
-fn void_type() -> Type {
-    syn::parse_quote!(())
+/// finds `Self::X<'a, 'b>` of generic associated types
+struct FindGats<'a> {
+    trait_name: &'a Ident,
+    associate_types: &'a [syn::TraitItemType],
+    found: Vec<(Ident, Vec<syn::Lifetime>)>,
 }
-#[allow(clippy::too_many_arguments)]
-fn gen_impl(
-    trait_name: Ident,
-    func_name: Ident,
-    self_input: ReceiverType,
-    func_arg_ids: Vec<Ident>,
-    func_arg_tys: Vec<Type>,
-    func_out_type: FuncOutput,
-    func_liftimes: Vec<LifetimeParam>,
-    func_is_unsafe: bool,
-    supertraits: Vec<syn::TypeParamBound>,
-    trait_generics: Vec<syn::GenericParam>,
-    trait_where: Vec<syn::WherePredicate>,
-    associate_types: Vec<syn::TraitItemType>,
-) -> TokenStream {
-    let fn_trait = match self_input {
-        ReceiverType::None | ReceiverType::Ref(_) => quote!(std::ops::Fn),
-        ReceiverType::Mut(_) => quote!(std::ops::FnMut),
-        ReceiverType::Owned => quote!(std::ops::FnOnce),
-    };
 
-    let self_receiver = match self_input {
-        ReceiverType::None => quote!(),
-        ReceiverType::Ref(t) => {
-            let liftimes = t.lifetime;
-            quote!(&#liftimes self)
-        }
-        ReceiverType::Mut(t) => {
-            let liftimes = t.lifetime;
-            quote!(&#liftimes mut self)
+impl<'ast> Visit<'ast> for FindGats<'_> {
+    fn visit_type_path(&mut self, i: &'ast TypePath) {
+        if let Some((v, segment)) = associate_type_of(i, self.trait_name, self.associate_types) {
+            if !v.generics.params.is_empty() {
+                let lifetimes = match &segment.arguments {
+                    syn::PathArguments::AngleBracketed(a) => a
+                        .args
+                        .iter()
+                        .filter_map(|v| match v {
+                            syn::GenericArgument::Lifetime(l) => Some(l.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+                self.found.push((v.ident.clone(), lifetimes));
+            }
         }
-        ReceiverType::Owned => quote!(self),
-    };
+        visit::visit_type_path(self, i);
+    }
+}
 
-    let for_liftime = {
-        if func_liftimes.is_empty() {
-            quote!()
-        } else {
-            quote!(
-                for<#(#func_liftimes),*>
-            )
-        }
-    };
+/// finds `Self` that is not in `Self::X` of associated types
+struct FindSelf<'a> {
+    trait_name: &'a Ident,
+    associate_types: &'a [syn::TraitItemType],
+    found: Vec<TypePath>,
+}
 
-    let func_liftime_generics = {
-        if func_liftimes.is_empty() {
-            quote!()
+impl<'ast> Visit<'ast> for FindSelf<'_> {
+    fn visit_type_path(&mut self, i: &'ast TypePath) {
+        if associate_type_of(i, self.trait_name, self.associate_types).is_some() {
+            visit::visit_path(self, &i.path);
+        } else if i.qself.is_none() && i.path.is_ident("Self") {
+            self.found.push(i.clone());
         } else {
-            quote!(<#(#func_liftimes),*>)
+            visit::visit_type_path(self, i);
         }
-    };
+    }
+}
 
-    let func_is_unsafe = {
-        if func_is_unsafe {
-            quote!(unsafe)
-        } else {
-            quote!()
-        }
-    };
-    let supertraits = {
-        if supertraits.is_empty() {
-            quote!()
-        } else {
-            quote!(: #(#supertraits)+*)
-        }
-    };
+/// replaces lifetimes, used to put the lifetimes of the method into generic associated types
+struct SubstLifetimes(Vec<(syn::Lifetime, syn::Lifetime)>);
 
-    let trait_generics_generics = {
-        if trait_generics.is_empty() {
-            quote!()
-        } else {
-            quote!(#(#trait_generics,)*)
+impl VisitMut for SubstLifetimes {
+    fn visit_lifetime_mut(&mut self, i: &mut syn::Lifetime) {
+        if let Some((_, v)) = self.0.iter().find(|(from, _)| from == i) {
+            *i = v.clone();
         }
-    };
-    let trait_generics_trait = {
-        if trait_generics.is_empty() {
-            quote!()
-        } else {
-            // let a = quote::quote! {'a};
-            // let a = a.into_iter().next().unwrap();
-            let t = trait_generics.iter().map(|p| match p {
-                syn::GenericParam::Lifetime(lt) => {
-                    let lt = &lt.lifetime;
-                    quote! {#lt}
-                }
-                syn::GenericParam::Type(ty) => {
-                    let ty = &ty.ident;
+    }
+}
+
+/// `dyn Trait` of the bounds of an erased type, `what` names it in errors at `span`
+fn dyn_of_bounds(
+    what: &str,
+    span: impl ToTokens,
+    bounds: &[&syn::TypeParamBound],
+) -> syn::Result<Type> {
+    let is_trait = |b: &&&syn::TypeParamBound| matches!(b, syn::TypeParamBound::Trait(_));
+    if bounds.iter().filter(is_trait).any(|b| {
+        matches!(b, syn::TypeParamBound::Trait(t) if matches!(t.modifier, syn::TraitBoundModifier::Maybe(_)))
+    }) {
+        Err(syn::Error::new_spanned(
+            &span,
+            format!("{} can not be `?Sized`", what),
+        ))?
+    }
+    let auto_traits = ["Send", "Sync", "Unpin", "UnwindSafe", "RefUnwindSafe"];
+    if bounds
+        .iter()
+        .filter(is_trait)
+        .filter(|b| match b {
+            syn::TypeParamBound::Trait(t) => t
+                .path
+                .segments
+                .last()
+                .is_none_or(|v| !auto_traits.iter().any(|a| v.ident == a)),
+            _ => false,
+        })
+        .count()
+        != 1
+    {
+        Err(syn::Error::new_spanned(
+            &span,
+            format!(
+                "{} needs exactly one trait bound that is not an auto trait",
+                what
+            ),
+        ))?
+    }
+    Ok(syn::parse_quote!(dyn #(#bounds)+*))
+}
+
+/// `T` of a type parameter
+fn is_type_param(t: &Type, ident: &Ident) -> bool {
+    matches!(t, Type::Path(p) if p.qself.is_none() && p.path.is_ident(ident))
+}
+
+fn mentions_ident(tokens: TokenStream, ident: &Ident) -> bool {
+    tokens.into_iter().any(|v| match v {
+        proc_macro2::TokenTree::Ident(v) => v == *ident,
+        proc_macro2::TokenTree::Group(v) => mentions_ident(v.stream(), ident),
+        _ => false,
+    })
+}
+
+/// one bound for each predicate
+fn split_predicates(
+    predicates: impl IntoIterator<Item = syn::WherePredicate>,
+) -> Vec<syn::WherePredicate> {
+    predicates
+        .into_iter()
+        .flat_map(|p| match p {
+            syn::WherePredicate::Type(v) => v
+                .bounds
+                .iter()
+                .map(|b| {
+                    let mut v = v.clone();
+                    v.bounds = Punctuated::from_iter([b.clone()]);
+                    syn::WherePredicate::Type(v)
+                })
+                .collect(),
+            syn::WherePredicate::Lifetime(v) => v
+                .bounds
+                .iter()
+                .map(|b| {
+                    let mut v = v.clone();
+                    v.bounds = Punctuated::from_iter([b.clone()]);
+                    syn::WherePredicate::Lifetime(v)
+                })
+                .collect(),
+            p => vec![p],
+        })
+        .collect()
+}
+
+fn mentions_lifetimes(p: &syn::WherePredicate, lifetimes: &[LifetimeParam]) -> bool {
+    let mut captures = CapturesLifetimes {
+        lifetimes,
+        found: false,
+    };
+    captures.visit_where_predicate(p);
+    captures.found
+}
+
+/// `Self::X<'a>` to `Self::X`, generic associated types are not generic in the helper trait
+struct StripGatArgs<'a> {
+    trait_name: &'a Ident,
+    associate_types: &'a [syn::TraitItemType],
+}
+
+impl VisitMut for StripGatArgs<'_> {
+    fn visit_type_path_mut(&mut self, i: &mut TypePath) {
+        if associate_type_of(i, self.trait_name, self.associate_types).is_some() {
+            if let Some(v) = i.path.segments.last_mut() {
+                v.arguments = syn::PathArguments::None;
+            }
+        }
+        visit_mut::visit_type_path_mut(self, i);
+    }
+}
+
+/// replaces every `impl Trait` with `name(i)` and gets their bounds
+fn hoist_impl_traits(
+    t: &Type,
+    name: impl Fn(usize) -> Type,
+) -> (Type, Vec<Vec<syn::TypeParamBound>>) {
+    struct Hoist<F> {
+        name: F,
+        bounds: Vec<Vec<syn::TypeParamBound>>,
+    }
+    impl<F: Fn(usize) -> Type> VisitMut for Hoist<F> {
+        fn visit_type_mut(&mut self, i: &mut Type) {
+            if let Type::ImplTrait(v) = i {
+                // `impl Trait` in the bounds gets its generic first
+                visit_mut::visit_type_impl_trait_mut(self, v);
+                self.bounds.push(v.bounds.iter().cloned().collect());
+                *i = (self.name)(self.bounds.len() - 1);
+            } else {
+                visit_mut::visit_type_mut(self, i);
+            }
+        }
+    }
+    let mut t = t.clone();
+    let mut hoist = Hoist {
+        name,
+        bounds: vec![],
+    };
+    hoist.visit_type_mut(&mut t);
+    (t, hoist.bounds)
+}
+
+/// lifetimes in `use<'a, T>`
+fn precise_capture(bound: &syn::TypeParamBound) -> Option<Vec<&syn::Lifetime>> {
+    let syn::TypeParamBound::PreciseCapture(v) = bound else {
+        return None;
+    };
+    let lifetimes = v
+        .params
+        .iter()
+        .filter_map(|v| match v {
+            syn::CapturedParam::Lifetime(l) => Some(l),
+            _ => None,
+        })
+        .collect();
+    Some(lifetimes)
+}
+
+/// `use<..>` is not a bound of a generic
+fn without_precise_capture(bounds: &[syn::TypeParamBound]) -> Vec<&syn::TypeParamBound> {
+    bounds
+        .iter()
+        .filter(|v| precise_capture(v).is_none())
+        .collect()
+}
+
+/// finds lifetimes of the method, `'_` and elided references
+struct CapturesLifetimes<'a> {
+    lifetimes: &'a [LifetimeParam],
+    found: bool,
+}
+
+impl<'ast> Visit<'ast> for CapturesLifetimes<'_> {
+    fn visit_lifetime(&mut self, i: &'ast syn::Lifetime) {
+        if i.ident == "_" || self.lifetimes.iter().any(|v| v.lifetime == *i) {
+            self.found = true;
+        }
+    }
+    fn visit_type_reference(&mut self, i: &'ast TypeReference) {
+        if i.lifetime.is_none() {
+            self.found = true;
+        }
+        visit::visit_type_reference(self, i);
+    }
+    // `Fn(&T)` and `fn(&T)` have their own elision
+    fn visit_parenthesized_generic_arguments(
+        &mut self,
+        _: &'ast syn::ParenthesizedGenericArguments,
+    ) {
+    }
+    fn visit_type_bare_fn(&mut self, _: &'ast syn::TypeBareFn) {}
+}
+
+/// gives every elided lifetime a name,
+/// or replaces them with `output` once it is set
+#[derive(Default)]
+struct NameElidedLifetimes {
+    new_lifetimes: Vec<syn::Lifetime>,
+    output: Option<syn::Lifetime>,
+}
+
+impl NameElidedLifetimes {
+    fn next(&mut self) -> syn::Lifetime {
+        if let Some(v) = &self.output {
+            return v.clone();
+        }
+        let lifetime = lifetime_of_elided(self.new_lifetimes.len());
+        self.new_lifetimes.push(lifetime.clone());
+        lifetime
+    }
+}
+
+impl VisitMut for NameElidedLifetimes {
+    fn visit_lifetime_mut(&mut self, i: &mut syn::Lifetime) {
+        if i.ident == "_" {
+            *i = self.next();
+        }
+    }
+    fn visit_type_reference_mut(&mut self, i: &mut TypeReference) {
+        if i.lifetime.is_none() {
+            i.lifetime = Some(self.next());
+        }
+        visit_mut::visit_type_reference_mut(self, i);
+    }
+    fn visit_parenthesized_generic_arguments_mut(
+        &mut self,
+        _: &mut syn::ParenthesizedGenericArguments,
+    ) {
+    }
+    fn visit_type_bare_fn_mut(&mut self, _: &mut syn::TypeBareFn) {}
+}
+
+/// gets `T` from `impl Future<Output = T>`
+fn future_output(bounds: &Punctuated<syn::TypeParamBound, Token![+]>) -> Option<Type> {
+    if bounds.len() != 1 {
+        return None;
+    }
+    let syn::TypeParamBound::Trait(t) = bounds.first()? else {
+        return None;
+    };
+    let last = t.path.segments.last()?;
+    if last.ident != "Future" {
+        return None;
+    }
+    let syn::PathArguments::AngleBracketed(generics) = &last.arguments else {
+        return None;
+    };
+    generics.args.iter().find_map(|v| match v {
+        syn::GenericArgument::AssocType(a) if a.ident == "Output" => Some(a.ty.clone()),
+        _ => None,
+    })
+}
+// fn token_vec(vec: &Vec<impl ToTokens>) -> TokenStream {
+//     let tokens = vec.iter().map(ToTokens::into_token_stream).collect();
+//     tokens
+// }
+// fn print_token_vec(vec: &Vec<impl ToTokens>) {
+//     println!("{}", token_vec(vec));
+// }
+
+fn void_type() -> Type {
+    syn::parse_quote!(())
+}
+/// the trait and its method that `gen_impl` impls the trait for
+struct ImplInput {
+    trait_vis: syn::Visibility,
+    trait_is_unsafe: bool,
+    trait_name: Ident,
+    func_name: Ident,
+    self_input: ReceiverType,
+    func_arg_ids: Vec<Ident>,
+    func_arg_tys: Vec<Type>,
+    func_out_type: FuncOutput,
+    func_liftimes: Vec<LifetimeParam>,
+    func_type_params: Vec<syn::TypeParam>,
+    func_type_params_dyn: Vec<(Ident, Type)>,
+    func_is_unsafe: bool,
+    func_is_async: bool,
+    func_abi: Option<syn::Abi>,
+    func_attrs: Vec<syn::Attribute>,
+    func_arg_attrs: Vec<Vec<syn::Attribute>>,
+    supertraits: Vec<syn::TypeParamBound>,
+    trait_generics: Vec<syn::GenericParam>,
+    trait_where: Vec<syn::WherePredicate>,
+    func_where: Vec<syn::WherePredicate>,
+    associate_types: Vec<syn::TraitItemType>,
+    associate_types_fixed: Vec<(Ident, Type)>,
+    associate_consts: Vec<(syn::TraitItemConst, syn::Expr)>,
+}
+fn gen_impl(input: ImplInput) -> TokenStream {
+    let ImplInput {
+        trait_vis,
+        trait_is_unsafe,
+        trait_name,
+        func_name,
+        self_input,
+        func_arg_ids,
+        func_arg_tys,
+        func_out_type,
+        func_liftimes,
+        func_type_params,
+        func_type_params_dyn,
+        func_is_unsafe,
+        func_is_async,
+        func_abi,
+        func_attrs,
+        func_arg_attrs,
+        supertraits,
+        trait_generics,
+        trait_where,
+        func_where,
+        associate_types,
+        associate_types_fixed,
+        associate_consts,
+    } = input;
+    let fn_trait = match (&func_out_type, self_input.clone()) {
+        (
+            FuncOutput::Async(_),
+            ReceiverType::None | ReceiverType::Ref(_) | ReceiverType::Shared(_),
+        ) => quote!(std::ops::AsyncFn),
+        (FuncOutput::Async(_), ReceiverType::Mut(_) | ReceiverType::PinMut(_)) => {
+            quote!(std::ops::AsyncFnMut)
+        }
+        (FuncOutput::Async(_), ReceiverType::Owned | ReceiverType::Boxed(_)) => {
+            quote!(std::ops::AsyncFnOnce)
+        }
+        (_, ReceiverType::None | ReceiverType::Ref(_) | ReceiverType::Shared(_)) => {
+            quote!(std::ops::Fn)
+        }
+        (_, ReceiverType::Mut(_) | ReceiverType::PinMut(_)) => quote!(std::ops::FnMut),
+        (_, ReceiverType::Owned | ReceiverType::Boxed(_)) => quote!(std::ops::FnOnce),
+    };
+
+    // `Rc`, `Arc` and `Pin` are not callable, so call what they point to
+    let self_call = match self_input {
+        ReceiverType::Shared(_) => quote!((*self)),
+        ReceiverType::PinMut(_) => quote!((std::pin::Pin::get_mut(self))),
+        _ => quote!(self),
+    };
+
+    let self_bound = match self_input {
+        ReceiverType::PinMut(_) => quote!(std::marker::Unpin),
+        _ => quote!(),
+    };
+
+    let self_receiver = match self_input.clone() {
+        ReceiverType::None => quote!(),
+        ReceiverType::Ref(t) => {
+            let liftimes = t.lifetime;
+            quote!(&#liftimes self)
+        }
+        ReceiverType::Mut(t) => {
+            let liftimes = t.lifetime;
+            quote!(&#liftimes mut self)
+        }
+        ReceiverType::Owned => quote!(self),
+        ReceiverType::Boxed(t) | ReceiverType::Shared(t) | ReceiverType::PinMut(t) => {
+            quote!(self: #t)
+        }
+    };
+
+    // `for<'c: 'a>` is not allowed, the bounds are kept on the method
+    let for_liftime = {
+        if func_liftimes.is_empty() {
+            quote!()
+        } else {
+            let liftimes = func_liftimes.iter().map(|v| &v.lifetime);
+            quote!(
+                for<#(#liftimes),*>
+            )
+        }
+    };
+
+    let func_liftime_generics = {
+        if func_liftimes.is_empty() && func_type_params.is_empty() {
+            quote!()
+        } else {
+            quote!(<#(#func_liftimes,)* #(#func_type_params),*>)
+        }
+    };
+    let mentions_type_params = |tokens: TokenStream| {
+        func_type_params
+            .iter()
+            .any(|v| mentions_ident(tokens.clone(), &v.ident))
+    };
+
+    let trait_is_unsafe = {
+        if trait_is_unsafe {
+            quote!(unsafe)
+        } else {
+            quote!()
+        }
+    };
+    // the forwarding body is always worth inlining
+    let func_attrs = {
+        let inline =
+            (!func_attrs.iter().any(|v| v.path().is_ident("inline"))).then(|| quote!(#[inline]));
+        quote!(#inline #(#func_attrs)*)
+    };
+    let func_arg_attrs = func_arg_attrs
+        .iter()
+        .map(|v| quote!(#(#v)*))
+        .collect::<Vec<_>>();
+
+    // `unsafe fn` pointers do not implement `Fn`, they get their own impls,
+    // unless bounds on `Self` would be bounds on a concrete fn pointer that may not hold
+    let impl_fn_pointers = {
+        let mut find_self = FindSelf {
+            trait_name: &trait_name,
+            associate_types: &associate_types,
+            found: vec![],
+        };
+        trait_where
+            .iter()
+            .for_each(|v| find_self.visit_where_predicate(v));
+        func_is_unsafe
+            && matches!(func_out_type, FuncOutput::Type(_) | FuncOutput::Impl(_))
+            && supertraits
+                .iter()
+                .all(|v| matches!(v, syn::TypeParamBound::Lifetime(_)))
+            && find_self.found.is_empty()
+    };
+    let fn_pointer_supertraits = {
+        if supertraits.is_empty() {
+            quote!()
+        } else {
+            quote!(Self: #(#supertraits)+*,)
+        }
+    };
+    let func_is_unsafe = {
+        if func_is_unsafe {
+            quote!(unsafe)
+        } else {
+            quote!()
+        }
+    };
+    // `dyn Fn` can be called through every receiver but `self`
+    let is_sized = self_input == ReceiverType::Owned
+        || supertraits
+            .iter()
+            .any(|v| matches!(v, syn::TypeParamBound::Trait(t) if t.path.is_ident("Sized")));
+    let unsized_bound = (!is_sized).then(|| quote!(: ?Sized));
+    let supertraits = {
+        let unsized_bound = (!is_sized).then(|| quote!(?Sized));
+        let supertraits = unsized_bound
+            .into_iter()
+            .chain(supertraits.iter().map(|v| quote!(#v)));
+        quote!(: #(#supertraits)+*)
+    };
+
+    // defaults and attributes are only allowed on the trait
+    let trait_generics_generics = {
+        if trait_generics.is_empty() {
+            quote!()
+        } else {
+            let iter = trait_generics.iter().cloned().map(|mut p| {
+                match &mut p {
+                    syn::GenericParam::Lifetime(v) => v.attrs.clear(),
+                    syn::GenericParam::Type(v) => {
+                        v.attrs.clear();
+                        v.eq_token = None;
+                        v.default = None;
+                    }
+                    syn::GenericParam::Const(v) => {
+                        v.attrs.clear();
+                        v.eq_token = None;
+                        v.default = None;
+                    }
+                }
+                p
+            });
+            quote!(#(#iter,)*)
+        }
+    };
+    let trait_generics_args = {
+        // let a = quote::quote! {'a};
+        // let a = a.into_iter().next().unwrap();
+        trait_generics
+            .iter()
+            .map(|p| match p {
+                syn::GenericParam::Lifetime(lt) => {
+                    let lt = &lt.lifetime;
+                    quote! {#lt}
+                }
+                syn::GenericParam::Type(ty) => {
+                    let ty = &ty.ident;
                     quote! {#ty}
                 }
                 syn::GenericParam::Const(co) => {
                     let co = &co.ident;
                     quote! { #co}
                 }
-            });
-            quote!(<#(#t),*>)
+            })
+            .collect::<Vec<_>>()
+    };
+    let trait_generics_trait = {
+        if trait_generics_args.is_empty() {
+            quote!()
+        } else {
+            quote!(<#(#trait_generics_args),*>)
         }
     };
 
@@ -349,99 +1380,706 @@ fn gen_impl(
         Span::call_site(),
     );
 
+    // `Self::X` can not be used in the impl, the helper trait keeps the original ones
+    let mut replace = ReplaceAssociateTypes {
+        trait_name: &trait_name,
+        associate_types: &associate_types,
+        associate_types_fixed: &associate_types_fixed,
+    };
+    let func_arg_tys_ori = func_arg_tys.clone();
+    let func_arg_tys = {
+        let mut v = func_arg_tys;
+        v.iter_mut().for_each(|v| replace.visit_type_mut(v));
+        v
+    };
+    // `T` and `impl Trait` are passed as `Box<dyn Trait>`, references to them as references to `dyn Trait`
+    let (func_arg_tys_closure, func_arg_calls): (Vec<Type>, Vec<TokenStream>) = func_arg_ids
+        .iter()
+        .zip(&func_arg_tys)
+        .map(|(id, t)| {
+            let find = |t: &Type| match t {
+                Type::ImplTrait(v) => {
+                    let bounds = &v.bounds;
+                    Some(syn::parse_quote!(dyn #bounds))
+                }
+                t => func_type_params_dyn
+                    .iter()
+                    .find(|(ident, _)| is_type_param(t, ident))
+                    .map(|(_, v)| v.clone()),
+            };
+            // `&(impl A + B)`
+            let find = |t: &Type| match t {
+                Type::Paren(p) => find(&p.elem),
+                t => find(t),
+            };
+            match t {
+                Type::Reference(r) => match find(&r.elem) {
+                    Some(v) => {
+                        let mut r = r.clone();
+                        *r.elem = syn::parse_quote!((#v));
+                        (Type::Reference(r), quote!(#id))
+                    }
+                    None => (t.clone(), quote!(#id)),
+                },
+                t => match find(t) {
+                    // `Box<dyn Trait>` would need `T: 'static`
+                    Some(Type::TraitObject(mut v)) => {
+                        if !v
+                            .bounds
+                            .iter()
+                            .any(|b| matches!(b, syn::TypeParamBound::Lifetime(_)))
+                        {
+                            v.bounds.push(syn::parse_quote!('_));
+                        }
+                        (
+                            syn::parse_quote!(std::boxed::Box<#v>),
+                            quote!(std::boxed::Box::new(#id)),
+                        )
+                    }
+                    Some(v) => (
+                        syn::parse_quote!(std::boxed::Box<#v>),
+                        quote!(std::boxed::Box::new(#id)),
+                    ),
+                    None => (t.clone(), quote!(#id)),
+                },
+            }
+        })
+        .unzip();
+    let trait_where_ori = { quote!(#(#trait_where),*) };
+    // predicates of the method without its lifetimes also bound the closure,
+    // the ones with its lifetimes can not leave the method
+    let func_where_impl = split_predicates(func_where.clone())
+        .into_iter()
+        .filter(|v| {
+            !mentions_lifetimes(v, &func_liftimes) && !mentions_type_params(v.to_token_stream())
+        });
+    let trait_where = {
+        let mut v = trait_where;
+        v.extend(func_where_impl);
+        v.iter_mut()
+            .for_each(|v| replace.visit_where_predicate_mut(v));
+        v
+    };
+    // the helper trait has the lifetimes of the method without bounds, so they become predicates
+    let func_where_ori = {
+        let mut v = func_where.clone();
+        v.extend(func_liftimes.iter().filter(|v| !v.bounds.is_empty()).map(
+            |v| -> syn::WherePredicate {
+                let (lifetime, bounds) = (&v.lifetime, &v.bounds);
+                syn::parse_quote!(#lifetime: #bounds)
+            },
+        ));
+        v
+    };
+    let func_where_helper = {
+        if func_where_ori.is_empty() {
+            quote!()
+        } else {
+            quote!(where #(#func_where_ori),*)
+        }
+    };
+    let func_where = {
+        if func_where.is_empty() {
+            quote!()
+        } else {
+            quote!(where #(#func_where),*)
+        }
+    };
+    let func_out_type_ori = func_out_type.clone();
+    let func_out_type = {
+        let mut v = func_out_type;
+        match &mut v {
+            FuncOutput::Type(t)
+            | FuncOutput::Async(t)
+            | FuncOutput::Gat(t, _)
+            | FuncOutput::Impl(t)
+            | FuncOutput::HigherRanked(t) => replace.visit_type_mut(t),
+        }
+        v
+    };
+    // every `impl Trait` in the output is a generic
+    let func_out_generics_name = |i: usize| {
+        Ident::new(
+            &format!("{}_{}", func_out_generic_name, i),
+            Span::call_site(),
+        )
+    };
+    let hoist = |v: &FuncOutput, self_path: bool| match v {
+        FuncOutput::Impl(t) | FuncOutput::HigherRanked(t) => hoist_impl_traits(t, |i| {
+            let ident = func_out_generics_name(i);
+            if self_path {
+                syn::parse_quote!(Self::#ident)
+            } else {
+                syn::parse_quote!(#ident)
+            }
+        }),
+        _ => (void_type(), vec![]),
+    };
+    let (func_out_hoisted, func_out_bounds) = hoist(&func_out_type, false);
+    let (func_out_hoisted_ori, func_out_bounds_ori) = hoist(&func_out_type_ori, true);
+    let func_out_generics = (0..func_out_bounds.len())
+        .map(func_out_generics_name)
+        .collect::<Vec<_>>();
+    // the lifetimes of the method a generic associated type is used with
+    let gat_lifetimes = {
+        let mut find = FindGats {
+            trait_name: &trait_name,
+            associate_types: &associate_types,
+            found: vec![],
+        };
+        if let FuncOutput::Gat(t, _) = &func_out_type_ori {
+            find.visit_type(t);
+        }
+        find.found
+    };
+    // the bounds and where clauses of generic associated types with the lifetimes of the method
+    let (associate_types_bounds_ori, associate_types_where_ori) = {
+        associate_types
+            .iter()
+            .map(|v| {
+                let mut bounds = v.bounds.clone();
+                let mut predicates = v
+                    .generics
+                    .where_clause
+                    .iter()
+                    .flat_map(|v| v.predicates.clone())
+                    .collect::<Vec<_>>();
+                if let Some((_, lifetimes)) = gat_lifetimes.iter().find(|(i, _)| *i == v.ident) {
+                    let mut subst = SubstLifetimes(
+                        v.generics
+                            .lifetimes()
+                            .map(|v| v.lifetime.clone())
+                            .zip(lifetimes.iter().cloned())
+                            .collect(),
+                    );
+                    bounds
+                        .iter_mut()
+                        .for_each(|v| subst.visit_type_param_bound_mut(v));
+                    predicates
+                        .iter_mut()
+                        .for_each(|v| subst.visit_where_predicate_mut(v));
+                }
+                (bounds, predicates)
+            })
+            .unzip::<_, _, Vec<_>, Vec<_>>()
+    };
+    let associate_types_bounds = {
+        associate_types_bounds_ori
+            .iter()
+            .map(|v| {
+                let mut bounds = v.clone();
+                bounds
+                    .iter_mut()
+                    .for_each(|v| replace.visit_type_param_bound_mut(v));
+                bounds
+            })
+            .collect::<Vec<_>>()
+    };
+    // the type of every associated type in the impl
+    let associate_types_target = {
+        associate_types
+            .iter()
+            .map(|v| {
+                let ident = &v.ident;
+                let mut t: Type = syn::parse_quote!(Self::#ident);
+                replace.visit_type_mut(&mut t);
+                t
+            })
+            .collect::<Vec<_>>()
+    };
+    let is_fixed = |ident: &Ident| associate_types_fixed.iter().any(|(v, _)| v == ident);
+
     let func_out = {
         match &func_out_type {
-            FuncOutput::Type(v) => {
-                if associate_types.is_empty() {
+            FuncOutput::Type(v) | FuncOutput::Async(v) | FuncOutput::Gat(v, _) => quote! {#v},
+            FuncOutput::Impl(_) | FuncOutput::HigherRanked(_) => {
+                quote! {#func_out_hoisted}
+            }
+        }
+    };
+
+    let func_out_trait = {
+        match (&func_out_type, &func_out_type_ori) {
+            // generic associated types are defined in the impl, use them as is
+            (_, FuncOutput::Gat(v, _)) => quote! {#v},
+            (FuncOutput::Type(v), _) => quote! {#v},
+            (FuncOutput::Async(v), _) => {
+                if func_is_async {
                     quote! {#v}
                 } else {
-                    let mut v1 = v.clone();
-                    replaced(&mut v1, &associate_types);
-                    quote! {#v1}
+                    quote! {impl std::future::Future<Output = #v>}
                 }
             }
-            FuncOutput::Impl(_) => quote! {#func_out_generic_name},
+            (FuncOutput::Impl(v) | FuncOutput::HigherRanked(v), _) => quote! {#v},
+            (FuncOutput::Gat(..), _) => unreachable!(),
         }
     };
 
-    let func_out_trait = {
-        match &func_out_type {
-            FuncOutput::Type(v) => {
-                if associate_types.is_empty() {
-                    quote! {#v}
-                } else {
-                    let mut v1 = v.clone();
-                    replaced(&mut v1, &associate_types);
-                    quote! {#v1}
+    let func_out_impl_trait_where = {
+        match &func_out_type {
+            FuncOutput::Type(_) | FuncOutput::Gat(..) => quote! {},
+            FuncOutput::Impl(_) => {
+                let iter = func_out_generics
+                    .iter()
+                    .zip(&func_out_bounds)
+                    .map(|(ident, v)| {
+                        let v = without_precise_capture(v);
+                        quote!(#ident : #(#v)+*,)
+                    });
+                quote! {#(#iter)*}
+            }
+            FuncOutput::Async(_) | FuncOutput::HigherRanked(_) => quote! {},
+        }
+    };
+
+    let func_out_generic_place = {
+        match &func_out_type {
+            FuncOutput::Type(_)
+            | FuncOutput::Async(_)
+            | FuncOutput::HigherRanked(_)
+            | FuncOutput::Gat(..) => quote! {},
+            FuncOutput::Impl(_) => quote! {#(#func_out_generics,)*},
+        }
+    };
+
+    let (func_is_async, func_await) = {
+        if func_is_async {
+            (quote!(async), quote!(.await))
+        } else {
+            (quote!(), quote!())
+        }
+    };
+
+    let trait_where = { quote!(#(#trait_where),*) };
+    let func_generic_name = Ident::new(
+        "FPleaseDontUsThisIdent1193r797g31r7jh930hc931rg",
+        Span::call_site(),
+    );
+
+    let associate_consts_impl = {
+        let iter = associate_consts.iter().map(|(c, e)| {
+            let ident = &c.ident;
+            let ty = &c.ty;
+            quote! {const #ident: #ty = #e;}
+        });
+        quote! {#(#iter)*}
+    };
+
+    let is_gat = |ident: &Ident| {
+        associate_types
+            .iter()
+            .any(|v| v.ident == *ident && !v.generics.params.is_empty())
+    };
+
+    // generic associated types only have a generic in the helper impl
+    let associate_types_generics_helper = {
+        let iter = associate_types
+            .iter()
+            .filter(|v| !is_fixed(&v.ident))
+            .map(|v| ident_of_associate_types_types_generics(&v.ident));
+        quote! {#(#iter, )*}
+    };
+    let associate_types_generics = {
+        let iter = associate_types
+            .iter()
+            .filter(|v| !is_fixed(&v.ident) && !is_gat(&v.ident))
+            .map(|v| ident_of_associate_types_types_generics(&v.ident));
+        quote! {#(#iter, )*}
+    };
+
+    let associate_types_generics_where_helper = {
+        let iter = associate_types
+            .iter()
+            .zip(&associate_types_bounds)
+            .filter(|(v, _)| !is_fixed(&v.ident))
+            .map(|(v, bounds)| {
+                let bounds = bounds.iter();
+                let ident = ident_of_associate_types_types_generics(&v.ident);
+                quote! {#ident : #(#bounds)+*}
+            });
+        quote! {#(#iter, )*}
+    };
+    let associate_types_generics_where = {
+        let iter = associate_types
+            .iter()
+            .zip(&associate_types_bounds)
+            .filter(|(v, _)| !is_fixed(&v.ident) && !is_gat(&v.ident))
+            .map(|(v, bounds)| {
+                let bounds = bounds.iter();
+                let ident = ident_of_associate_types_types_generics(&v.ident);
+                quote! {#ident : #(#bounds)+*}
+            });
+        quote! {#(#iter, )*}
+    };
+
+    let associate_types_generics_impl_helper = {
+        let iter = associate_types
+            .iter()
+            .zip(&associate_types_target)
+            .map(|(v, target)| {
+                let ident_ori = &v.ident;
+                quote! {type #ident_ori = #target;}
+            });
+        quote! {#(#iter)*}
+    };
+
+    // `use<..>` in an impl has to list every generic of the impl,
+    // and only the lifetimes listed in the trait
+    let func_out_trait = match &func_out_type {
+        FuncOutput::Impl(v) | FuncOutput::HigherRanked(v) => {
+            let generics = trait_generics
+                .iter()
+                .filter_map(|p| match p {
+                    syn::GenericParam::Lifetime(_) => None,
+                    syn::GenericParam::Type(ty) => Some(&ty.ident),
+                    syn::GenericParam::Const(co) => Some(&co.ident),
+                })
+                .cloned()
+                .chain(
+                    func_out_generics
+                        .iter()
+                        .filter(|_| matches!(func_out_type, FuncOutput::Impl(_)))
+                        .cloned(),
+                )
+                .chain(
+                    associate_types
+                        .iter()
+                        .filter(|v| !is_fixed(&v.ident) && !is_gat(&v.ident))
+                        .map(|v| ident_of_associate_types_types_generics(&v.ident)),
+                )
+                .chain([func_generic_name.clone()])
+                .collect::<Vec<_>>();
+            struct TranslatePreciseCapture(Vec<Ident>);
+            impl VisitMut for TranslatePreciseCapture {
+                fn visit_type_param_bound_mut(&mut self, i: &mut syn::TypeParamBound) {
+                    if let Some(lifetimes) = precise_capture(i) {
+                        let generics = &self.0;
+                        *i = syn::parse_quote!(use<#(#lifetimes,)* #(#generics),*>);
+                    }
+                    visit_mut::visit_type_param_bound_mut(self, i);
                 }
             }
-            FuncOutput::Impl(v) => quote! {
-                impl #(#v)+*
-            },
+            let mut v = v.clone();
+            TranslatePreciseCapture(generics).visit_type_mut(&mut v);
+            quote! {#v}
         }
+        _ => func_out_trait,
     };
 
-    let func_out_impl_trait_where = {
-        match &func_out_type {
-            FuncOutput::Type(_) => quote! {},
-            FuncOutput::Impl(v) => quote! {
-                #func_out_generic_name : #(#v)+*,
-            },
-        }
+    let helper_liftimes = match &func_out_type_ori {
+        // only the lifetimes in `use<..>` can be in the output
+        FuncOutput::HigherRanked(_) => Some(
+            func_liftimes
+                .iter()
+                .map(|v| v.lifetime.clone())
+                .filter(|l| {
+                    func_out_bounds_ori
+                        .iter()
+                        .flatten()
+                        .filter_map(precise_capture)
+                        .all(|lifetimes| lifetimes.contains(&l))
+                })
+                .collect::<Vec<_>>(),
+        ),
+        FuncOutput::Gat(_, lifetimes) => Some(lifetimes.clone()),
+        _ => None,
     };
 
-    let func_out_generic_place = {
-        match &func_out_type {
-            FuncOutput::Type(_) => quote! {},
-            FuncOutput::Impl(_) => quote! {#func_out_generic_name,},
+    // predicates with the lifetimes of the helper trait but no other lifetimes of the method,
+    // `for<'c>` can not have bounds so outlives are implied by an argument of the helper trait
+    let (helper_implied, helper_where) = {
+        let lifetimes = helper_liftimes
+            .iter()
+            .flatten()
+            .map(|v| LifetimeParam::new(v.clone()))
+            .collect::<Vec<_>>();
+        let inner_lifetimes = func_liftimes
+            .iter()
+            .filter(|v| !lifetimes.iter().any(|l| l.lifetime == v.lifetime))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut implied: Vec<Type> = vec![];
+        let mut where_predicates = vec![];
+        let predicates = func_where_ori
+            .iter()
+            .chain(associate_types_where_ori.iter().flatten())
+            .cloned();
+        for mut p in split_predicates(predicates) {
+            if lifetimes.is_empty()
+                || !mentions_lifetimes(&p, &lifetimes)
+                || mentions_lifetimes(&p, &inner_lifetimes)
+                || mentions_type_params(p.to_token_stream())
+            {
+                continue;
+            }
+            replace.visit_where_predicate_mut(&mut p);
+            match &p {
+                syn::WherePredicate::Type(syn::PredicateType {
+                    lifetimes: None,
+                    bounded_ty,
+                    bounds,
+                    ..
+                }) if matches!(bounds.first(), Some(syn::TypeParamBound::Lifetime(_))) => {
+                    let l = bounds.first();
+                    implied.push(syn::parse_quote!(&#l #bounded_ty));
+                }
+                syn::WherePredicate::Lifetime(v) => {
+                    let (a, b) = (&v.lifetime, v.bounds.first());
+                    implied.push(syn::parse_quote!(&#b &#a ()));
+                }
+                _ => where_predicates.push(p),
+            }
         }
+        // the arguments are well formed in the closure, so the helper impl can assume it
+        let mentions = |t: &Type, lifetimes: &[LifetimeParam]| {
+            let mut captures = CapturesLifetimes {
+                lifetimes,
+                found: false,
+            };
+            captures.visit_type(t);
+            captures.found
+        };
+        implied.extend(
+            func_arg_tys
+                .iter()
+                .filter(|t| {
+                    !lifetimes.is_empty()
+                        && mentions(t, &lifetimes)
+                        && !mentions(t, &inner_lifetimes)
+                        && !mentions_type_params(t.to_token_stream())
+                        && hoist_impl_traits(t, |_| void_type()).1.is_empty()
+                })
+                .cloned(),
+        );
+        (quote!((#(#implied,)*)), where_predicates)
     };
 
-    let trait_where = { quote!(#(#trait_where),*) };
-    let func_generic_name = Ident::new(
-        "FPleaseDontUsThisIdent1193r797g31r7jh930hc931rg",
-        Span::call_site(),
-    );
-
-    let associate_types_generics = {
+    let associate_types_generics_impl = {
+        let helper_args = {
+            let lifetimes = helper_liftimes.iter().flatten();
+            quote!(<#(#lifetimes,)* #(#trait_generics_args,)* #helper_implied>)
+        };
+        let helper_name = ident_of_helper_trait(&trait_name);
         let iter = associate_types
             .iter()
-            .map(|v| ident_of_associate_types_types_generics(&v.ident));
-        quote! {#(#iter, )*}
+            .zip(&associate_types_target)
+            .map(|(v, target)| {
+                let ident_ori = &v.ident;
+                match gat_lifetimes.iter().find(|(i, _)| i == ident_ori) {
+                    Some((_, lifetimes)) => {
+                        // every lifetime of the helper trait is one of the parameters
+                        let params = v.generics.lifetimes().map(|v| &v.lifetime).collect::<Vec<_>>();
+                        let mut subst = SubstLifetimes(
+                            lifetimes
+                                .iter()
+                                .cloned()
+                                .zip(params.iter().map(|v| (*v).clone()))
+                                .collect(),
+                        );
+                        let mut helper_args: syn::AngleBracketedGenericArguments =
+                            syn::parse2(helper_args.clone()).unwrap();
+                        subst.visit_angle_bracketed_generic_arguments_mut(&mut helper_args);
+                        let generics = &v.generics;
+                        let where_clause = &v.generics.where_clause;
+                        quote! {type #ident_ori #generics = <#func_generic_name as #helper_name #helper_args>::#ident_ori #where_clause;}
+                    }
+                    None => quote! {type #ident_ori = #target;},
+                }
+            });
+        quote! {#(#iter)*}
     };
 
-    let associate_types_generics_where = {
-        let iter = associate_types.iter().map(|v| {
-            let bounds = v.bounds.iter();
-            let ident = ident_of_associate_types_types_generics(&v.ident);
-            quote! {#ident : #(#bounds)+*}
-        });
-        quote! {#(#iter, )*}
+    // the method of every impl of the trait, `async` is only there without the helper trait and fn pointers
+    let func_sig = quote! {
+        #func_attrs
+        #func_is_async #func_is_unsafe #func_abi fn #func_name #func_liftime_generics (#self_receiver, #(#func_arg_attrs #func_arg_ids:#func_arg_tys),* ) -> #func_out_trait #func_where
     };
+    // impls the trait for `self_ty`, with extra `generics` and `bound`
+    let impl_trait = |generics: TokenStream,
+                      self_ty: TokenStream,
+                      bound: TokenStream,
+                      body: TokenStream| {
+        quote!(
+            #[allow(non_camel_case_types)]
+            #trait_is_unsafe impl<#trait_generics_generics #func_out_generic_place #associate_types_generics #generics> #trait_name #trait_generics_trait for #self_ty where
+                #func_out_impl_trait_where
+                #associate_types_generics_where
+                #bound
+                #trait_where
+                {
+                    #associate_types_generics_impl
+                    #associate_consts_impl
 
-    let associate_types_generics_impl = {
-        let iter = associate_types.iter().map(|v| {
-            let ident_target = ident_of_associate_types_types_generics(&v.ident);
-            let ident_ori = &v.ident;
-            quote! {type #ident_ori = #ident_target;}
+                    #func_sig {
+                        #body
+                    }
+                }
+        )
+    };
+
+    if let Some(helper_liftimes) = &helper_liftimes {
+        let helper_name = ident_of_helper_trait(&trait_name);
+        // a different name so that calling the method is not ambiguous
+        let helper_func_name = Ident::new(
+            "call_please_dont_us_this_ident_1193r797g31r7jh930hc931rg",
+            Span::call_site(),
+        );
+        // lifetimes of the method that the output does not depend on
+        let inner_liftimes = func_liftimes
+            .iter()
+            .map(|v| &v.lifetime)
+            .filter(|v| !helper_liftimes.contains(v))
+            .collect::<Vec<_>>();
+        let (inner_for, inner_generics) =
+            match (inner_liftimes.is_empty(), func_type_params.is_empty()) {
+                (true, true) => (quote!(), quote!()),
+                (true, false) => (quote!(), quote!(<#(#func_type_params),*>)),
+                _ => (
+                    quote!(for<#(#inner_liftimes),*>),
+                    quote!(<#(#inner_liftimes,)* #(#func_type_params),*>),
+                ),
+            };
+        let func_liftimes = helper_liftimes;
+        let helper_args = quote!(<#(#func_liftimes,)* #(#trait_generics_args,)* #helper_implied>);
+        let helper_implied_name = Ident::new(
+            "ImpliedPleaseDontUsThisIdent1193r797g31r7jh930hc931rg",
+            Span::call_site(),
+        );
+        let associate_types_helper = associate_types
+            .iter()
+            .zip(&associate_types_bounds_ori)
+            .zip(&associate_types_where_ori)
+            .map(|((v, bounds), predicates)| {
+                let ident = &v.ident;
+                let colon = &v.colon_token;
+                if predicates.is_empty() {
+                    quote! {type #ident #colon #bounds;}
+                } else {
+                    quote! {type #ident #colon #bounds where #(#predicates),*;}
+                }
+            });
+        let associate_types_bindings = associate_types
+            .iter()
+            .zip(&associate_types_target)
+            .filter(|(v, _)| !is_gat(&v.ident))
+            .map(|(v, target)| {
+                let ident_ori = &v.ident;
+                quote! {#ident_ori = #target,}
+            });
+        let (helper_out_decl, helper_out_generic, helper_out_where, helper_out_impl) =
+            match (&func_out_type_ori, &func_out_type) {
+                (FuncOutput::HigherRanked(_), FuncOutput::HigherRanked(_)) => {
+                    let bounds_ori = func_out_bounds_ori
+                        .iter()
+                        .map(|v| without_precise_capture(v));
+                    let bounds = func_out_bounds.iter().map(|v| without_precise_capture(v));
+                    let bounds_ori = bounds_ori.map(|v| quote!(#(#v)+*));
+                    let bounds = bounds.map(|v| quote!(#(#v)+*));
+                    let names = &func_out_generics;
+                    (
+                        quote!(#(type #names: #bounds_ori;)*),
+                        quote!(#(#names,)*),
+                        quote!(#(#names : #bounds,)*),
+                        quote!(#(type #names = #names;)*),
+                    )
+                }
+                _ => (quote!(), quote!(), quote!(), quote!()),
+            };
+        let (helper_fn_out, helper_fn_out_impl) = match (&func_out_type_ori, &func_out_type) {
+            (FuncOutput::Gat(t_ori, _), FuncOutput::Gat(t, _)) => {
+                let mut t_ori = t_ori.clone();
+                StripGatArgs {
+                    trait_name: &trait_name,
+                    associate_types: &associate_types,
+                }
+                .visit_type_mut(&mut t_ori);
+                (quote!(#t_ori), quote!(#t))
+            }
+            _ => (quote!(#func_out_hoisted_ori), quote!(#func_out_hoisted)),
+        };
+        let main_impl = impl_trait(
+            quote!(#func_generic_name #supertraits),
+            quote!(#func_generic_name),
+            quote!(#func_generic_name: for<#(#func_liftimes),*> #helper_name <#(#func_liftimes,)* #(#trait_generics_args,)* #helper_implied, #(#associate_types_bindings)*> + #self_bound,),
+            quote!(<#func_generic_name as #helper_name #helper_args>::#helper_func_name(self, #(#func_arg_ids),*)),
+        );
+        return quote::quote!(
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            #trait_vis trait #helper_name <#(#func_liftimes,)* #trait_generics_generics #helper_implied_name> where
+                #trait_where_ori
+                {
+                    #(#associate_types_helper)*
+                    #helper_out_decl
+
+                    fn #helper_func_name #inner_generics (#self_receiver, #(#func_arg_ids:#func_arg_tys_ori),* ) -> #helper_fn_out #func_where_helper;
+                }
+
+            #[allow(non_camel_case_types)]
+            impl<#(#func_liftimes,)* #trait_generics_generics #helper_out_generic #associate_types_generics_helper #func_generic_name #unsized_bound> #helper_name #helper_args for #func_generic_name where
+                #helper_out_where
+                #(#helper_where,)*
+                #associate_types_generics_where_helper
+                #func_generic_name: #inner_for #fn_trait(#(#func_arg_tys_closure),*) -> #helper_fn_out_impl + #self_bound,
+                #trait_where
+                {
+                    #associate_types_generics_impl_helper
+                    #helper_out_impl
+
+                    #[inline]
+                    fn #helper_func_name #inner_generics (#self_receiver, #(#func_arg_ids:#func_arg_tys),* ) -> #helper_fn_out_impl #func_where_helper {
+                        #self_call(#(#func_arg_calls),*)
+                    }
+                }
+
+            #main_impl
+        );
+    }
+
+    let fn_pointer_impls = {
+        let self_call = match self_input {
+            ReceiverType::Owned => quote!(self),
+            _ => quote!((*self)),
+        };
+        let abis = [quote!(), quote!(extern "C")];
+        let iter = abis.iter().filter(|_| impl_fn_pointers).map(|abi| {
+            impl_trait(
+                quote!(),
+                quote!(#for_liftime unsafe #abi fn(#(#func_arg_tys_closure),*) -> #func_out),
+                fn_pointer_supertraits.clone(),
+                quote!(unsafe { #self_call(#(#func_arg_calls),*) }),
+            )
         });
-        quote! {#(#iter)*}
+        quote!(#(#iter)*)
     };
 
+    let main_impl = impl_trait(
+        quote!(#func_generic_name #supertraits),
+        quote!(#func_generic_name),
+        quote!(#func_generic_name: #for_liftime #fn_trait(#(#func_arg_tys_closure),*) ->#func_out + #self_bound,),
+        quote!(#self_call(#(#func_arg_calls),*) #func_await),
+    );
+
     quote::quote!(
-        #[allow(non_camel_case_types)]
-        impl<#trait_generics_generics #func_out_generic_place #associate_types_generics #func_generic_name #supertraits> #trait_name #trait_generics_trait for #func_generic_name where
-            #func_out_impl_trait_where
-            #associate_types_generics_where
-            #func_generic_name: #for_liftime #fn_trait(#(#func_arg_tys),*) ->#func_out,
-            #trait_where
-            {
-                #associate_types_generics_impl
+        #main_impl
 
-                #func_is_unsafe fn #func_name #func_liftime_generics (#self_receiver, #(#func_arg_ids:#func_arg_tys),* ) -> #func_out_trait{
-                    self(#(#func_arg_ids),*)
-                }
-            }
+        #fn_pointer_impls
+    )
+}
+fn ident_of_helper_trait(ident: &Ident) -> Ident {
+    let helper_trait_name_base = "FHelperPleaseDontUsThisIdent1193r797g31r7jh930hc931rg";
+    Ident::new(
+        &format!("{}_{}", helper_trait_name_base, ident),
+        Span::call_site(),
+    )
+}
+fn lifetime_of_elided(id: usize) -> syn::Lifetime {
+    syn::Lifetime::new(
+        &format!(
+            "'life{}_please_dont_us_this_ident_1193r797g31r7jh930hc931rg",
+            id
+        ),
+        Span::call_site(),
     )
 }
 fn ident_of_associate_types_types_generics(ident: &Ident) -> Ident {
@@ -451,141 +2089,758 @@ fn ident_of_associate_types_types_generics(ident: &Ident) -> Ident {
         Span::call_site(),
     )
 }
-fn replaced(t: &mut Type, associate_types: &[syn::TraitItemType]) {
-    // let ident = ident_of_associate_types_types_generics(&associate_types[0].ident);
+fn ident_of_arg(id: usize) -> Ident {
+    Ident::new(
+        &format!(
+            "arg{}_please_dont_us_this_ident_1193r797g31r7jh930hc931rg",
+            id
+        ),
+        Span::call_site(),
+    )
+}
+/// replaces `Self::X` and `<Self as Trait>::X` with the generic of the associated type `X`,
+/// or with its type if it is fixed
+struct ReplaceAssociateTypes<'a> {
+    trait_name: &'a Ident,
+    associate_types: &'a [syn::TraitItemType],
+    associate_types_fixed: &'a [(Ident, Type)],
+}
 
-    // *t = syn::parse_quote!(#ident);
-    match t {
-        Type::Array(a) => {
-            replaced(&mut a.elem, associate_types);
-        }
-        Type::BareFn(f) => {
-            f.inputs
-                .iter_mut()
-                .for_each(|v| replaced(&mut v.ty, associate_types));
-            match &mut f.output {
-                syn::ReturnType::Default => todo!(),
-                syn::ReturnType::Type(_, t) => replaced(t, associate_types),
-            };
-        }
-        Type::Group(g) => {
-            replaced(&mut g.elem, associate_types);
+/// finds the associated type of `Self::X` or `<Self as Trait>::X`
+fn associate_type_of<'a, 'p>(
+    p: &'p TypePath,
+    trait_name: &Ident,
+    associate_types: &'a [syn::TraitItemType],
+) -> Option<(&'a syn::TraitItemType, &'p syn::PathSegment)> {
+    let segments = &p.path.segments;
+    let ident = match &p.qself {
+        None => {
+            if segments.len() != 2 || segments[0].ident != "Self" {
+                return None;
+            }
+            &segments[1]
         }
-        Type::ImplTrait(_t) => {}
-        Type::Infer(_) => {}
-        Type::Macro(_) => {}
-        Type::Never(_) => {}
-        Type::Paren(p) => {
-            replaced(&mut p.elem, associate_types);
+        Some(q) => {
+            if !is_self(&q.ty)
+                || q.position == 0
+                || segments.len() != q.position + 1
+                || segments[q.position - 1].ident != *trait_name
+            {
+                return None;
+            }
+            &segments[q.position]
         }
-        Type::Path(p) => {
-            // let l = p.path.segments.iter_mut().last().unwrap();
-            if let Some(v) = associate_types.iter().find(|v| {
-                let ident = &v.ident;
-                let p1: TypePath = syn::parse_quote!(Self::#ident);
-                format!("{}", quote! {#p1}) == format!("{}", quote! {#p})
-            }) {
-                let ident = ident_of_associate_types_types_generics(&v.ident);
+    };
+    let v = associate_types.iter().find(|v| v.ident == ident.ident)?;
+    // only generic associated types have arguments
+    if !ident.arguments.is_empty() && v.generics.params.is_empty() {
+        return None;
+    }
+    Some((v, ident))
+}
 
-                *t = syn::parse_quote!(#ident);
-            };
-        }
-        Type::Ptr(v) => {
-            replaced(&mut v.elem, associate_types);
-        }
-        Type::Reference(r) => {
-            replaced(&mut r.elem, associate_types);
-        }
-        Type::Slice(s) => {
-            replaced(&mut s.elem, associate_types);
-        }
-        Type::TraitObject(_t) => {
-            // t.bounds.iter_mut().for_each(|_b| {});
+impl VisitMut for ReplaceAssociateTypes<'_> {
+    fn visit_type_mut(&mut self, i: &mut Type) {
+        if let Type::Path(p) = i {
+            if let Some((v, _)) = associate_type_of(p, self.trait_name, self.associate_types) {
+                if let Some((_, t)) = self
+                    .associate_types_fixed
+                    .iter()
+                    .find(|(v1, _)| *v1 == v.ident)
+                {
+                    *i = t.clone();
+                    visit_mut::visit_type_mut(self, i);
+                } else {
+                    let ident = ident_of_associate_types_types_generics(&v.ident);
+                    *i = syn::parse_quote!(#ident);
+                }
+                return;
+            }
         }
-        Type::Tuple(t) => {
-            t.elems
-                .iter_mut()
-                .for_each(|e| replaced(e, associate_types));
+        visit_mut::visit_type_mut(self, i);
+    }
+}
+
+/// the trait is still checked with the default arguments if the arguments are wrong
+fn expend_with_args(input: ItemTrait, args: TokenStream) -> syn::Result<TokenStream> {
+    match syn::parse2::<Args>(args) {
+        Ok(args) => expend(input, args),
+        Err(mut e) => {
+            if let Err(v) = expend(input, Args::default()) {
+                e.combine(v);
+            }
+            Err(e)
         }
-        Type::Verbatim(_) => {}
-        _ => {}
     }
 }
 
 ///
 ///
-/// A simple macro that inspired by java's functional interface.
+/// A simple macro that inspired by java's functional interface.
+///
+/// the macro impls a trait for [Fn], [FnMut] or [FnOnce] when the trait:
+///
+/// - contains one and only one method without a default body
+///
+/// - the method has a receiver, and the receiver is `&self`, `&mut self`, `self`,
+///   `self: Box<Self>`, `self: Rc<Self>`, `self: Arc<Self>` or `self: Pin<&mut Self>`
+///
+/// - has no generic types or `impl Trait` arguments in the method, unless they are erased with `erase_generics`
+///
+/// - has no `Self` in the argument types, other than `Self::X` of associated types
+///
+/// - is not unsafe, unless `unsafe_impl` is given
+///
+/// # Example
+///
+/// ### basic usage
+///
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait E<'a, T: 'a + ?Sized, const AA: usize, T1>: Sized + Clone + Send
+/// where
+///     T1: Send + Sync,
+///     T: std::fmt::Display,
+/// {
+///     unsafe fn e<'c>(&'c self, a: &'a T, b: [i32; AA], t1: T1) -> &'a str;
+/// }
+///
+/// let fe = |a: &str, b: [i32; 4], _c: i128| {
+///     dbg!(a);
+///     dbg!(b);
+///     "413"
+/// };
+/// unsafe { fe.e("4fr13", [3, 5, 1, 1], 9) };
+///
+/// ```
+///
+///
+/// ### use as helper trait
+/// ```rust
+/// use functional_trait::functional_trait;
+/// use std::future::Future;
+/// #[functional_trait]
+/// trait Helper<'a> {
+///     fn call(&self, s: &'a str) -> impl 'a + Future<Output = &'a str>;
+/// }
+///
+/// async fn async1(s: &str) -> &str {
+///     println!("{}", s);
+///     s
+/// }
+/// fn take_async(f: impl for<'a> Helper<'a>) {
+///     let string = "aaa".to_owned();
+///     let fut = f.call(&string);
+///     // drop(string1);
+///     drop(fut);
+///     drop(string);
+/// }
+/// take_async(async1);
+/// ```
+///
+/// ### async fn
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Handler {
+///     async fn handle(&self, x: u32) -> u32;
+/// }
+///
+/// async fn plus_one(x: u32) -> u32 {
+///     x + 1
+/// }
+/// let fut = plus_one.handle(1);
+/// drop(fut);
+/// let fut = (|x: u32| async move { x * 2 }).handle(2);
+/// drop(fut);
+/// let fut = (async |x: u32| x * 3).handle(3);
+/// drop(fut);
+/// ```
+///
+/// ### async closures
+///
+/// `async fn` methods are implemented for [AsyncFn], [AsyncFnMut] or [AsyncFnOnce],
+/// so the future may borrow the arguments.
+/// With `#[functional_trait(async_fn)]` the same is done for methods returning `impl Future<Output = ..>`.
+/// ```rust
+/// use functional_trait::functional_trait;
+/// use std::future::Future;
+///
+/// #[functional_trait]
+/// trait Echo {
+///     async fn echo<'x>(&self, s: &'x str) -> &'x str;
+/// }
+///
+/// #[functional_trait(async_fn)]
+/// trait Helper {
+///     fn call<'x>(&self, s: &'x str) -> impl Future<Output = &'x str>;
+/// }
+///
+/// async fn async1(s: &str) -> &str {
+///     s
+/// }
+/// fn take_async(f: impl Helper) {
+///     let string = "aaa".to_owned();
+///     let fut = f.call(&string);
+///     drop(fut);
+///     drop(string);
+/// }
+/// drop(async1.echo("aaa"));
+/// take_async(async1);
+/// ```
+///
+/// ### associated types
+///
+/// associated types are inferred from the output of the closure
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Parse {
+///     type Output;
+///     type Error;
+///     fn parse(&self, s: &str) -> Result<Vec<Self::Output>, Self::Error>;
+/// }
+///
+/// let p = |s: &str| s.split(',').map(str::parse).collect::<Result<Vec<i32>, _>>();
+/// assert_eq!(p.parse("1,2"), Ok(vec![1, 2]));
+/// ```
+///
+/// associated types that can not be inferred, like the ones only used in arguments,
+/// can be fixed with `#[functional_trait(Name = Type)]`
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait(Input = String, Meta = ())]
+/// trait Service {
+///     type Input;
+///     type Output;
+///     type Meta;
+///     fn call(&self, input: Self::Input) -> Self::Output;
+/// }
+///
+/// let s = |input: String| input.len();
+/// assert_eq!(Service::call(&s, "abc".to_owned()), 3);
+/// ```
+///
+/// generic associated types with lifetimes of the method are allowed in the output,
+/// the closure then has to return a type for every lifetime
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Split {
+///     type Iter<'x>: Iterator<Item = &'x str>;
+///     fn split<'x>(&self, s: &'x str) -> Self::Iter<'x>;
+/// }
+///
+/// fn by_comma(s: &str) -> std::str::Split<'_, char> {
+///     s.split(',')
+/// }
+/// assert_eq!(by_comma.split("a,b").collect::<Vec<_>>(), ["a", "b"]);
+/// ```
+///
+/// ### associated consts
+///
+/// associated consts use their default, or the value given with `#[functional_trait(NAME = value)]`
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait(NAME = "json")]
+/// trait Format {
+///     const NAME: &'static str;
+///     const PRIORITY: u8 = 0;
+///     fn format(&self, x: u32) -> String;
+/// }
+///
+/// fn name<F: Format>(_: &F) -> (&'static str, u8) {
+///     (F::NAME, F::PRIORITY)
+/// }
+/// let f = |x: u32| format!("{{\"x\":{}}}", x);
+/// assert_eq!(f.format(1), "{\"x\":1}");
+/// assert_eq!(name(&f), ("json", 0));
+/// ```
+///
+/// ### generic methods
+///
+/// with `#[functional_trait(erase_generics)]` type parameters of the method used as `T`, `&T` or `&mut T`
+/// are passed to the closure as `Box<dyn Trait>`, `&dyn Trait` or `&mut dyn Trait`
+/// ```rust
+/// use functional_trait::functional_trait;
+/// use std::fmt::Display;
+///
+/// #[functional_trait(erase_generics)]
+/// trait Log {
+///     fn log<T: Display>(&self, v: &T) -> String;
+/// }
+///
+/// let l = |v: &dyn Display| format!("[{}]", v);
+/// assert_eq!(l.log(&1), "[1]");
+/// assert_eq!(l.log(&"a"), "[a]");
+/// ```
+///
+/// `impl Trait` in arguments is erased the same way
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait(erase_generics)]
+/// trait Sum {
+///     fn sum(&self, it: impl Iterator<Item = u8>) -> u32;
+/// }
+///
+/// let s = |it: Box<dyn Iterator<Item = u8> + '_>| it.map(u32::from).sum();
+/// assert_eq!(s.sum([1, 2, 3].into_iter()), 6);
+/// ```
 ///
-/// the macro impls a trait for [Fn], [FnMut] or [FnOnce] when the trait:
+/// ### other receivers
 ///
-/// - contains one and only one method
+/// `self: Box<Self>` is implemented for [FnOnce], `self: Rc<Self>` and `self: Arc<Self>` for [Fn],
+/// and `self: Pin<&mut Self>` for [FnMut] closures that are [Unpin].
+/// ```rust
+/// use functional_trait::functional_trait;
 ///
-/// - the method has a receiver, and the receiver is `&self`, `&mut self` or `self`
+/// #[functional_trait]
+/// trait Callback {
+///     fn call_once(self: Box<Self>, x: i32) -> i32;
+/// }
 ///
-/// - has no generic types in the method
+/// let s = String::from("a");
+/// let cb: Box<dyn Callback> = Box::new(move |x| {
+///     drop(s);
+///     x + 1
+/// });
+/// assert_eq!(cb.call_once(1), 2);
+/// ```
 ///
-/// - is not unsafe
+/// ```rust
+/// use functional_trait::functional_trait;
+/// use std::{pin::Pin, rc::Rc, sync::Arc};
 ///
-/// # Example
+/// #[functional_trait]
+/// trait Local {
+///     fn call_rc(self: Rc<Self>, x: i32) -> i32;
+/// }
 ///
-/// ### basic usage
+/// #[functional_trait]
+/// trait Broadcast {
+///     fn call_arc(self: Arc<Self>, x: i32) -> i32;
+/// }
+///
+/// #[functional_trait]
+/// trait Counter {
+///     fn count(self: Pin<&mut Self>, x: i32) -> i32;
+/// }
+///
+/// let rc = Rc::new(|x| x + 1);
+/// assert_eq!(rc.clone().call_rc(1), 2);
+/// assert_eq!(rc.call_rc(2), 3);
+///
+/// let arc: Arc<dyn Broadcast> = Arc::new(|x| x * 2);
+/// assert_eq!(arc.call_arc(2), 4);
+///
+/// let mut n = 0;
+/// let mut counter = |x| {
+///     n += x;
+///     n
+/// };
+/// assert_eq!(Pin::new(&mut counter).count(1), 1);
+/// assert_eq!(Pin::new(&mut counter).count(2), 3);
+/// ```
+///
+/// ### unsafe traits
+///
+/// an unsafe trait is only implemented with `#[functional_trait(unsafe_impl)]`,
+/// which promises that every closure of the signature upholds the contract of the trait
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// /// # Safety
+/// /// the callback can be called from any thread
+/// #[functional_trait(unsafe_impl)]
+/// unsafe trait ForeignCallback: Send + Sync {
+///     fn call(&self, code: i32) -> i32;
+/// }
+///
+/// fn register(cb: impl ForeignCallback + 'static) -> i32 {
+///     std::thread::spawn(move || cb.call(1)).join().unwrap()
+/// }
+///
+/// assert_eq!(register(|code| code + 1), 2);
+/// ```
+///
+/// ### attributes
 ///
+/// `#[inline]`, `#[cold]`, `#[track_caller]`, `#[cfg]`, `#[cfg_attr]` and lint attributes of the method
+/// and attributes of its arguments other than `#[cfg]` and `#[cfg_attr]` are kept in the impl,
+/// which is `#[inline]` if not told otherwise
 /// ```rust
 /// use functional_trait::functional_trait;
 ///
 /// #[functional_trait]
-/// trait E<'a, T: 'a + ?Sized, const AA: usize, T1>: Sized + Clone + Send
-/// where
-///     T1: Send + Sync,
-///     T: std::fmt::Display,
-/// {
-///     unsafe fn e<'c>(&'c self, a: &'a T, b: [i32; AA], t1: T1) -> &'a str;
+/// trait Check {
+///     #[track_caller]
+///     fn check(&self, #[allow(unused_mut)] x: i32) -> bool;
 /// }
 ///
-/// let fe = |a: &str, b: [i32; 4], _c: i128| {
-///     dbg!(a);
-///     dbg!(b);
-///     "413"
-/// };
-/// unsafe { fe.e("4fr13", [3, 5, 1, 1], 9) };
+/// assert!((|x| x > 0).check(1));
+/// ```
+///
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait T1 {
+///     #[cfg(all())]
+///     fn f(&self, x: i32) -> i32;
+/// }
 ///
+/// assert_eq!((|x| x + 1).f(1), 2);
 /// ```
 ///
+/// arguments of the closure can not be left out
+/// ```compile_fail
+/// use functional_trait::functional_trait;
 ///
-/// ### use as helper trait
+/// #[functional_trait]
+/// trait T2 {
+///     fn f(&self, #[cfg(any())] x: i32, y: i32) -> i32;
+/// }
+/// ```
+///
+/// `#[cfg]` can not pick one of the methods, the macro sees the trait before it is cfg-stripped
+/// ```compile_fail
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Pick {
+///     #[cfg(any())]
+///     fn gone(&self, x: i32) -> i32;
+///     fn here(&self, x: i32) -> i32;
+/// }
+/// ```
+///
+/// ### extern fn
+///
+/// the ABI of the method is kept in the impl
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Callback {
+///     extern "C" fn call(&self, x: i32) -> i32;
+/// }
+///
+/// assert_eq!((|x| x + 1).call(1), 2);
+/// ```
+///
+/// ### unsafe fn pointers
+///
+/// an `unsafe fn` method is also implemented for `unsafe fn` and `unsafe extern "C" fn` pointers,
+/// unless the output is async or depends on the lifetimes of the method through `impl Trait`
+/// or generic associated types, or the trait has supertraits or bounds on `Self`
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Callback {
+///     unsafe fn call(&self, x: i32) -> i32;
+/// }
+///
+/// unsafe extern "C" fn add_one(x: i32) -> i32 {
+///     x + 1
+/// }
+///
+/// let cb: unsafe extern "C" fn(i32) -> i32 = add_one;
+/// assert_eq!(unsafe { cb.call(1) }, 2);
+/// ```
+///
+/// a supertrait of the closures only does not stop the closures from implementing the trait
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// trait Marker {}
+/// impl<F: Fn(i32) -> i32> Marker for F {}
+///
+/// #[functional_trait]
+/// trait Cb: Marker {
+///     unsafe fn call(&self, x: i32) -> i32;
+/// }
+///
+/// assert_eq!(unsafe { (|x| x + 1).call(1) }, 2);
+/// ```
+///
+/// ### unsized closures
+///
+/// the trait is also implemented for `dyn Fn`, unless the receiver is `self` or the trait needs [Sized]
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Map {
+///     fn map(&self, x: i32) -> i32;
+/// }
+///
+/// fn take(m: &(impl Map + ?Sized)) -> i32 {
+///     m.map(1)
+/// }
+///
+/// let f: Box<dyn Fn(i32) -> i32> = Box::new(|x| x + 1);
+/// assert_eq!(take(&*f), 2);
+/// ```
+///
+/// ### patterns in arguments
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait P {
+///     fn p(&self, (a, b): (i32, i32), _: u8) -> i32 {
+///         a + b
+///     }
+/// }
+///
+/// let fp = |t: (i32, i32), _: u8| t.0 * t.1;
+/// assert_eq!(fp.p((2, 3), 0), 6);
+/// ```
+///
+/// ### provided methods
+///
+/// methods and consts with a default body are not counted, only the method without a body is implemented.
+/// a trait with a single method is still implemented if the method has a default body
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Step {
+///     fn step(&self, x: i32) -> i32;
+///     fn step_twice(&self, x: i32) -> i32 {
+///         self.step(self.step(x))
+///     }
+/// }
+///
+/// let s = |x: i32| x + 1;
+/// assert_eq!(s.step_twice(1), 3);
+/// ```
+///
+/// ### where clauses of the method
+///
+/// the where clause is kept on the method, predicates without lifetimes of the method also bound the closure
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait First<T> {
+///     fn first<'c>(&self, x: &'c T) -> Option<T::Item>
+///     where
+///         T: Iterator + Clone + 'c;
+/// }
+///
+/// let f = |x: &std::ops::Range<u8>| x.clone().next();
+/// assert_eq!(f.first(&(3..5)), Some(3));
+/// ```
+///
+/// bounds of the lifetimes of the method are kept on the method too,
+/// the closure has to accept any lifetime
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Pick<'a> {
+///     fn pick<'c: 'a>(&self, x: &'c str, y: &'a str) -> &'c str;
+/// }
+///
+/// fn first<'c>(x: &'c str, _: &str) -> &'c str {
+///     x
+/// }
+/// assert_eq!(first.pick("a", "b"), "a");
+/// ```
+///
+/// ### defaults of generics
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Handler<Req, Resp = (), const N: usize = 4> {
+///     fn handle(&self, req: Req) -> Resp;
+/// }
+///
+/// fn run<H: Handler<u32>>(h: H) {
+///     h.handle(1)
+/// }
+/// run(|x: u32| println!("{}", x));
+/// ```
+///
+/// ### `impl Trait` in the output
+///
+/// every `impl Trait` in the output can be a different type
+/// ```rust
+/// use functional_trait::functional_trait;
+/// use std::fmt::Display;
+///
+/// #[functional_trait]
+/// trait Pair {
+///     fn pair(&self, x: u8) -> (impl Display, Box<impl Iterator<Item = u8>>);
+/// }
+///
+/// let p = |x: u8| (x, Box::new(0..x));
+/// let (a, b) = p.pair(3);
+/// assert_eq!(a.to_string(), "3");
+/// assert_eq!(b.count(), 3);
+/// ```
+///
+/// nested `impl Trait` gets its own generic too
+/// ```rust
+/// use functional_trait::functional_trait;
+/// use std::fmt::Display;
+///
+/// #[functional_trait]
+/// trait Items {
+///     fn items(&self) -> impl Iterator<Item = impl Display>;
+/// }
+///
+/// let i = || vec![1, 2].into_iter();
+/// let v: Vec<String> = i.items().map(|v| v.to_string()).collect();
+/// assert_eq!(v, ["1", "2"]);
+/// ```
+///
+/// an output borrowing from the arguments can use generics of the trait
+/// ```rust
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Lens<T> {
+///     fn lens<'a>(&self, items: &'a [T]) -> impl Iterator<Item = usize> + 'a;
+/// }
+///
+/// fn lens<T>(items: &[T]) -> impl Iterator<Item = usize> + '_ {
+///     items.iter().map(|_| 1)
+/// }
+/// assert_eq!(lens.lens(&["a", "b"]).sum::<usize>(), 2);
+/// ```
+///
+/// without `use<..>` the output captures every lifetime of the method, like `impl Trait` in traits does
 /// ```rust
 /// use functional_trait::functional_trait;
 /// use std::future::Future;
+///
 /// #[functional_trait]
-/// trait Helper<'a> {
-///     fn call(&self, s: &'a str) -> impl 'a + Future<Output = &'a str>;
+/// trait E3 {
+///     fn f<'c>(&self, s: &'c str) -> impl Future<Output = usize>;
 /// }
 ///
-/// async fn async1(s: &str) -> &str {
-///     println!("{}", s);
-///     s
+/// fn g<'c>(s: &'c str) -> impl Future<Output = usize> + 'c {
+///     async move { s.len() }
 /// }
-/// fn take_async(f: impl for<'a> Helper<'a>) {
-///     let string = "aaa".to_owned();
-///     let fut = f.call(&string);
-///     // drop(string1);
-///     drop(fut);
-///     drop(string);
+/// let s = String::from("ab");
+/// let fut = g.f(&s);
+/// drop(fut);
+/// ```
+///
+/// ### precise capturing
+///
+/// `use<..>` in the output is kept, the closure only has to return a type for the lifetimes in it
+/// ```rust
+/// use functional_trait::functional_trait;
+/// use std::future::Future;
+///
+/// #[functional_trait]
+/// trait Save<'a> {
+///     fn save<'c>(&self, name: &'c str, data: &'a [u8]) -> impl Future<Output = usize> + use<'a, Self>;
+/// }
+///
+/// fn save<'a>(name: &str, data: &'a [u8]) -> impl Future<Output = usize> + use<'a> {
+///     println!("{}", name);
+///     async move { data.len() }
+/// }
+/// let name = String::from("a");
+/// let fut = save.save(&name, &[1, 2]);
+/// drop(name);
+/// drop(fut);
+/// ```
+///
+/// ### errors
+///
+/// `Self` in the arguments is not the closure, so it is rejected
+/// ```compile_fail
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Merge {
+///     fn merge(&self, other: &Self) -> i32;
+/// }
+/// ```
+///
+/// errors point at the offending tokens, like the receiver
+/// ```compile_fail
+/// use functional_trait::functional_trait;
+/// use std::rc::Rc;
+///
+/// #[functional_trait]
+/// trait Area {
+///     fn area(self: &Rc<Self>) -> f64;
+/// }
+/// ```
+///
+/// generics of the method
+/// ```compile_fail
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Apply {
+///     fn apply<T>(&self, x: T) -> T;
+/// }
+/// ```
+///
+/// every required method after the first one
+/// ```compile_fail
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// trait Two {
+///     fn one(&self) -> i32;
+///     fn two(&self) -> i32;
+/// }
+/// ```
+///
+/// or an unknown argument of the macro
+/// ```compile_fail
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait(erase_generic)]
+/// trait Show {
+///     fn show(&self, x: &dyn std::fmt::Display) -> String;
+/// }
+/// ```
+///
+/// all errors are reported together, and the trait is kept so that code using it only fails with them
+/// ```compile_fail
+/// use functional_trait::functional_trait;
+///
+/// #[functional_trait]
+/// unsafe trait Parse {
+///     fn a<T>(&self, x: T) -> i32;
+///     fn b(&self) -> i32;
+/// }
+///
+/// fn parse(p: &impl Parse) -> i32 {
+///     p.b()
 /// }
-/// take_async(async1);
 /// ```
-
 ///
 ///
 #[proc_macro_attribute]
 pub fn functional_trait(
-    _args: proc_macro::TokenStream,
+    args: proc_macro::TokenStream,
     input: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    let a = || -> Result<proc_macro::TokenStream, String> {
+    let a = || -> syn::Result<proc_macro::TokenStream> {
         let input: TokenStream = input.into();
-        let d: syn::ItemTrait = syn::parse2(input.clone()).map_err(|e| e.to_string())?;
-        let a: TokenStream = expend(d)?.into_token_stream();
+        let d: syn::ItemTrait = syn::parse2(input.clone())?;
+        // the trait is kept on errors, so that code using it still type checks
+        let a = expend_with_args(d, args.into()).unwrap_or_else(|e| e.to_compile_error());
         Ok(quote!(
             #input
             #a
@@ -594,7 +2849,7 @@ pub fn functional_trait(
     };
     match a() {
         Ok(v) => v,
-        Err(e) => quote! {compile_error!(#e);}.into(),
+        Err(e) => e.to_compile_error().into(),
     }
 }
 
@@ -623,8 +2878,65 @@ fn a() {
 
     let d: syn::ItemTrait = syn::parse2(d).unwrap();
 
-    let a: TokenStream = expend(d).unwrap().into_token_stream();
+    let a: TokenStream = expend(d, Args::default()).unwrap().into_token_stream();
+    println!("{}", a);
+
+    let d: TokenStream = quote!(
+        trait D {
+            type Error;
+            type Output;
+            fn d(&self, b: Self::Error) -> Result<Self::Output, Self::Error>;
+        }
+    );
+    let d: syn::ItemTrait = syn::parse2(d).unwrap();
+    let args: Args = syn::parse2(quote!(Error = std::io::Error)).unwrap();
+    let a: TokenStream = expend(d, args).unwrap().into_token_stream();
     println!("{}", a);
+    let a: String = a.to_string().split_whitespace().collect();
+    assert!(a.contains("typeError=std::io::Error;"));
+    assert!(!a.contains("FATPleaseDontUsThisIdent1193r797g31r7jh930hc931rg_Error"));
+    let d: TokenStream = quote!(
+        unsafe trait Parse {
+            fn a<T>(&self, x: T) -> i32;
+            fn b(&self) -> i32;
+        }
+    );
+    let d: syn::ItemTrait = syn::parse2(d).unwrap();
+    let e = expend(d, Args::default()).err().unwrap();
+    assert_eq!(e.into_iter().count(), 3);
+
+    // every error points at the offending tokens
+    let d: syn::ItemTrait = syn::parse_str(
+        "unsafe trait Parse { fn a<T>(self: &Rc<Self>, x: &Self) -> i32; fn b(&self) -> i32; }",
+    )
+    .unwrap();
+    let e = expend(d, Args::default()).err().unwrap();
+    let e: Vec<_> = e
+        .into_iter()
+        .map(|e| e.span().source_text().unwrap())
+        .collect();
+    assert_eq!(
+        e,
+        [
+            "unsafe",
+            "fn b(&self) -> i32;",
+            "T",
+            "self: &Rc<Self>",
+            "Self"
+        ]
+    );
+    let e = syn::parse_str::<Args>("async_fn, erase_generic")
+        .err()
+        .unwrap();
+    assert_eq!(e.span().source_text().unwrap(), "erase_generic");
+    let d: syn::ItemTrait = syn::parse_str("trait Two { fn a(&self); fn b(&self); }").unwrap();
+    let e = expend_with_args(d, quote!(erase_generic)).err().unwrap();
+    assert_eq!(e.into_iter().count(), 2);
+    for v in ["X = u8, X = u16", "X = u8, X = 1", "async_fn, async_fn"] {
+        let e = syn::parse_str::<Args>(v).err().unwrap();
+        assert_eq!(e.to_string(), "duplicate argument");
+        assert_eq!(e.span().start().column, v.find(", ").unwrap() + 2);
+    }
     let e = "ffff";
     println!("{}", quote! {compile_error!(#e);});
 }